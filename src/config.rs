@@ -0,0 +1,307 @@
+// Node configuration, loaded in layers: the defaults below, then a TOML file given with
+// `--config <file>`, then `--<key> <value>` flags, e.g. `--max-peers 2`
+//
+// Values which are part of consensus, like the difficulty and the genesis block, aren't
+// configurable, since all nodes on a network have to agree on them.
+use super::{DIFFICULTY_PREFIX, GENESIS_HASH};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+static CONFIG: OnceCell<Config> = OnceCell::new();
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // tcp port to listen on, 0 picks a random one
+    pub listen_port: u16,
+    // the network this node belongs to, which is the genesis hash - the node refuses to start
+    // if the genesis block doesn't hash to it, unset (the default) skips the check
+    pub network_id: Option<String>,
+    // maximum number of connected peers, in total - connections beyond it are closed
+    pub max_peers: u32,
+    // a read replica only syncs, validates and serves the chain, but never creates blocks
+    pub replica: bool,
+    // how many blocks of a received chain are validated before yielding back to the runtime
+    pub chain_validation_chunk_size: usize,
+    // verify the context-free rules of a chain's blocks in parallel, on rayon's thread pool
+    pub parallel_chain_validation: bool,
+    // blocks further ahead than MAX_FUTURE_DRIFT_SECS, but within this window, are queued and retried later
+    pub future_block_grace_window_secs: i64,
+    pub max_queued_future_blocks: usize,
+    // how many chain responses can be waiting to be published, before further ones are dropped
+    pub response_channel_capacity: usize,
+    // how many received blocks can be waiting for validation, before further ones are dropped
+    pub inbound_block_queue_capacity: usize,
+    // blocks from a peer are ignored once this many of them were dropped because the queue was
+    // full, until dropped_blocks_window_secs after the first of them (0 never ignores a peer)
+    pub max_dropped_blocks_per_peer: u64,
+    pub dropped_blocks_window_secs: i64,
+    // reject messages from peers which contain unknown fields, instead of ignoring those fields
+    pub strict_json: bool,
+    // drop received blocks with an invalid proof of work before queueing or validating them further
+    pub verify_pow_first: bool,
+    // new blocks carry the total work of the chain up to them, which makes comparing chains O(1)
+    pub include_total_work: bool,
+    // every block the node mines is appended as a JSON line to this file, for analyzing mining
+    // offline - maintenance commands mining blocks don't write to it
+    pub mining_log_path: Option<String>,
+    // rejected blocks received from peers are written to this directory, for forensics
+    pub quarantine_dir: Option<String>,
+    pub max_quarantined_blocks: usize,
+    // give up mining a block after this many seconds (0 disables)
+    pub max_mining_secs: u64,
+    // mine on a dedicated OS thread instead of the event loop, which keeps handling stdin and
+    // peers meanwhile
+    pub mining_thread: bool,
+    // niceness added to the mining thread on unix, a positive value lowers its priority
+    pub mining_thread_nice: i32,
+    // on a testnet, blocks mined long after their predecessor only need the minimum difficulty,
+    // see TESTNET_RESET_TIMEOUT_SECS - all nodes of a network have to agree on this
+    pub testnet: bool,
+    // how many hashrate samples, taken while mining, are kept for the hashrate command
+    pub max_hashrate_samples: usize,
+    // peer ids whose chains are adopted when syncing, if the response is signed by them - blocks
+    // are still accepted from everyone, empty trusts all peers
+    pub trusted_sync_peers: Vec<String>,
+    // request the chain on startup from the peer with the lowest measured latency first
+    pub prefer_low_latency_sync: bool,
+    // if the requested peer doesn't respond within this many seconds, the next one is asked
+    pub sync_timeout_secs: i64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen_port: 0,
+            network_id: None,
+            max_peers: 25,
+            replica: false,
+            chain_validation_chunk_size: 100,
+            parallel_chain_validation: true,
+            future_block_grace_window_secs: 60,
+            max_queued_future_blocks: 100,
+            response_channel_capacity: 16,
+            inbound_block_queue_capacity: 64,
+            max_dropped_blocks_per_peer: 100,
+            dropped_blocks_window_secs: 60,
+            strict_json: false,
+            verify_pow_first: true,
+            include_total_work: false,
+            mining_log_path: None,
+            quarantine_dir: None,
+            max_quarantined_blocks: 1000,
+            max_mining_secs: 0,
+            mining_thread: false,
+            mining_thread_nice: 0,
+            testnet: false,
+            max_hashrate_samples: 100,
+            trusted_sync_peers: vec![],
+            prefer_low_latency_sync: true,
+            sync_timeout_secs: 10,
+        }
+    }
+}
+
+// the config of this node, the defaults if it wasn't initialized (yet)
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+pub fn init(config: Config) {
+    if CONFIG.set(config).is_err() {
+        panic!("config is already initialized");
+    }
+}
+
+// Loads the config from the command line arguments, returns it along with the remaining
+// positional arguments (e.g. a maintenance subcommand)
+pub fn load(args: &[String]) -> Result<(Config, Vec<String>), String> {
+    let mut table = match toml::Value::try_from(Config::default()) {
+        Ok(toml::Value::Table(table)) => table,
+        _ => return Err("can't serialize default config".to_string()),
+    };
+    let mut file = None;
+    let mut overrides = vec![];
+    let mut positional = vec![];
+
+    let mut args = args.iter().peekable();
+    while let Some(arg) = args.next() {
+        let key = match arg.strip_prefix("--") {
+            Some(key) => key.replace('-', "_"),
+            None => {
+                positional.push(arg.clone());
+                continue;
+            }
+        };
+        if key == "config" {
+            file = Some(args.next().ok_or("--config needs a file")?.clone());
+            continue;
+        }
+        // boolean flags can be given without a value, e.g. `--replica`
+        let is_bool = matches!(table.get(&key), Some(toml::Value::Boolean(_)));
+        let value = match args.peek() {
+            Some(value) if !is_bool || *value == "true" || *value == "false" => {
+                args.next().expect("peeked value exists").clone()
+            }
+            _ if is_bool => "true".to_string(),
+            _ => return Err(format!("--{} needs a value", arg.trim_start_matches("--"))),
+        };
+        overrides.push((key, parse_value(&value)));
+    }
+
+    if let Some(file) = file {
+        let content =
+            fs::read_to_string(&file).map_err(|e| format!("can't read {}: {}", file, e))?;
+        let file_table = content
+            .parse::<toml::Table>()
+            .map_err(|e| format!("can't parse {}: {}", file, e))?;
+        table.extend(file_table);
+    }
+    table.extend(overrides);
+
+    let config = toml::Value::Table(table)
+        .try_into::<Config>()
+        .map_err(|e| e.to_string())?;
+    config.validate()?;
+    Ok((config, positional))
+}
+
+impl Config {
+    // rejects values the node can't run with
+    fn validate(&self) -> Result<(), String> {
+        let non_zero = [
+            (
+                "chain_validation_chunk_size",
+                self.chain_validation_chunk_size,
+            ),
+            ("response_channel_capacity", self.response_channel_capacity),
+            (
+                "inbound_block_queue_capacity",
+                self.inbound_block_queue_capacity,
+            ),
+        ];
+        for (key, value) in non_zero {
+            if value == 0 {
+                return Err(format!("{} has to be greater than 0", key));
+            }
+        }
+        Ok(())
+    }
+}
+
+// the resolved config as TOML, headed by the consensus values which aren't configurable
+pub fn show(config: &Config) -> String {
+    let toml = toml::to_string(config).expect("can serialize config");
+    format!(
+        "# difficulty: {} bits\n# genesis hash: {}\n{}",
+        DIFFICULTY_PREFIX.len(),
+        GENESIS_HASH,
+        toml
+    )
+}
+
+// parses a flag's value as a TOML value, falling back to a plain string, e.g. for paths
+fn parse_value(value: &str) -> toml::Value {
+    format!("value = {}", value)
+        .parse::<toml::Table>()
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(value.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    // writes a config file, named after the test so tests running in parallel don't share one
+    fn config_file(name: &str, content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "rust-blockchain-example-{}-{}.toml",
+            std::process::id(),
+            name
+        ));
+        fs::write(&path, content).expect("can write config file");
+        path
+    }
+
+    #[test]
+    fn defaults_without_arguments() {
+        let (config, positional) = load(&[]).expect("defaults are valid");
+        assert_eq!(config.max_peers, Config::default().max_peers);
+        // the network id check is opt-in
+        assert_eq!(config.network_id, None);
+        assert!(positional.is_empty());
+    }
+
+    #[test]
+    fn show_reflects_overrides() {
+        let (config, _) = load(&args(&["--max-peers", "5"])).expect("flags are valid");
+        let shown = show(&config);
+        assert!(shown.lines().any(|line| line == "max_peers = 5"));
+        // it can be used as a config file
+        let reloaded: Config = toml::from_str(&shown).expect("shown config is valid toml");
+        assert_eq!(reloaded.max_peers, 5);
+    }
+
+    #[test]
+    fn flags_override_the_file() {
+        let path = config_file(
+            "precedence",
+            "max_peers = 3\nreplica = true\nsync_timeout_secs = 20\n",
+        );
+        let (config, positional) = load(&args(&[
+            "maintenance",
+            "--config",
+            path.to_str().expect("temp dir is utf-8"),
+            "--max-peers",
+            "5",
+            "--replica",
+            "false",
+            "--testnet",
+        ]))
+        .expect("config is valid");
+        fs::remove_file(&path).expect("can remove config file");
+
+        assert_eq!(config.max_peers, 5);
+        assert!(!config.replica);
+        assert!(config.testnet);
+        // from the file, since no flag overrides it
+        assert_eq!(config.sync_timeout_secs, 20);
+        // a default, since neither sets it
+        assert_eq!(
+            config.chain_validation_chunk_size,
+            Config::default().chain_validation_chunk_size
+        );
+        assert_eq!(positional, args(&["maintenance"]));
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert!(load(&args(&["--no-such-key", "1"])).is_err());
+        let path = config_file("unknown", "no_such_key = 1\n");
+        let result = load(&args(&[
+            "--config",
+            path.to_str().expect("temp dir is utf-8"),
+        ]));
+        fs::remove_file(&path).expect("can remove config file");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn zero_capacities_are_rejected() {
+        for key in [
+            "--chain-validation-chunk-size",
+            "--response-channel-capacity",
+            "--inbound-block-queue-capacity",
+        ] {
+            assert!(load(&args(&[key, "0"])).is_err());
+            assert!(load(&args(&[key, "1"])).is_ok());
+        }
+    }
+}
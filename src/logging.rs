@@ -0,0 +1,94 @@
+// Logging, with a filter which can be changed at runtime via the `loglevel` command
+use env_logger::filter::{Builder, Filter};
+use log::{LevelFilter, Log, Metadata, Record};
+use once_cell::sync::OnceCell;
+use std::sync::RwLock;
+
+static LOGGER: OnceCell<RuntimeFilterLogger> = OnceCell::new();
+
+struct RuntimeFilterLogger {
+    // logs everything it gets, the filtering happens in `filter`
+    inner: env_logger::Logger,
+    filter: RwLock<Filter>,
+}
+
+impl Log for RuntimeFilterLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter
+            .read()
+            .expect("can read log filter")
+            .enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self
+            .filter
+            .read()
+            .expect("can read log filter")
+            .matches(record)
+        {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+impl RuntimeFilterLogger {
+    // replaces the filter, returns the most verbose level it lets through
+    fn set_filters(&self, filters: &str) -> LevelFilter {
+        let filter = Builder::new().parse(filters).build();
+        let max_level = filter.filter();
+        *self.filter.write().expect("can write log filter") = filter;
+        max_level
+    }
+}
+
+// initializes logging with the filters from RUST_LOG, like pretty_env_logger::init
+pub fn init() {
+    let inner = pretty_env_logger::formatted_builder()
+        .filter_level(LevelFilter::Trace)
+        .build();
+    let filter = Builder::from_env("RUST_LOG").build();
+    log::set_max_level(filter.filter());
+    let logger = LOGGER.get_or_init(|| RuntimeFilterLogger {
+        inner,
+        filter: RwLock::new(filter),
+    });
+    log::set_logger(logger).expect("can set logger");
+}
+
+// replaces the log filters, which use the same syntax as RUST_LOG, e.g. `info,rust_blockchain_example::p2p=debug`
+pub fn set_filters(filters: &str) {
+    if let Some(logger) = LOGGER.get() {
+        log::set_max_level(logger.set_filters(filters));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::{Level, MetadataBuilder};
+
+    #[test]
+    fn set_filters_changes_what_is_enabled() {
+        let logger = RuntimeFilterLogger {
+            inner: pretty_env_logger::formatted_builder().build(),
+            filter: RwLock::new(Builder::new().parse("info").build()),
+        };
+        let debug = |target| {
+            MetadataBuilder::new()
+                .level(Level::Debug)
+                .target(target)
+                .build()
+        };
+        assert!(!logger.enabled(&debug("rust_blockchain_example::p2p")));
+
+        let max_level = logger.set_filters("info,rust_blockchain_example::p2p=debug");
+        assert_eq!(max_level, LevelFilter::Debug);
+        assert!(logger.enabled(&debug("rust_blockchain_example::p2p")));
+        assert!(!logger.enabled(&debug("rust_blockchain_example")));
+    }
+}
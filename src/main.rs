@@ -1,33 +1,89 @@
 use chrono::prelude::*;
 use libp2p::{
-    core::upgrade,
+    core::{connection::ConnectionLimits, upgrade},
     futures::StreamExt,
     mplex,
     noise::{Keypair, NoiseConfig, X25519Spec},
-    swarm::{Swarm, SwarmBuilder},
+    swarm::{Swarm, SwarmBuilder, SwarmEvent},
     tcp::TokioTcpConfig,
-    Transport,
+    PeerId, Transport,
 };
 use log::{error, info, warn};
+use once_cell::sync::Lazy;
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::time::Duration;
+use std::{
+    collections::VecDeque,
+    fmt,
+    fs::OpenOptions,
+    io::Write,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
 use tokio::{
     io::{stdin, AsyncBufReadExt, BufReader},
     select, spawn,
     sync::mpsc,
-    time::sleep,
+    task::{block_in_place, yield_now},
+    time::{interval, sleep},
 };
 
-const DIFFICULTY_PREFIX: &str = "00";
+// 16 leading zero bits
+const DIFFICULTY_PREFIX: &str = "0000000000000000";
+const GENESIS_TIMESTAMP: i64 = 1636070400;
+const GENESIS_NONCE: u64 = 20897;
+const GENESIS_HASH: &str = "000063d3923a2152f7613993c67f7a5ac0c8ab0486f40a9a32132e219d619e94";
+// how many seconds a block's timestamp may be ahead of our clock
+const MAX_FUTURE_DRIFT_SECS: i64 = 15;
+// on a testnet, a block mined this long after its predecessor only needs the minimum difficulty,
+// which keeps the network going when hashpower drops
+const TESTNET_RESET_TIMEOUT_SECS: i64 = 600;
+// 8 leading zero bits
+const MIN_DIFFICULTY_PREFIX: &str = "00000000";
+
+// hashes per second measured when mining the last block
+static LAST_HASHRATE: Lazy<Mutex<Option<f64>>> = Lazy::new(|| Mutex::new(None));
+// hashrates sampled while mining, the oldest first, see Config::max_hashrate_samples
+static HASHRATE_HISTORY: Lazy<Mutex<VecDeque<HashrateSample>>> =
+    Lazy::new(|| Mutex::new(VecDeque::new()));
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HashrateSample {
+    pub timestamp: i64,
+    pub hashrate: f64,
+}
 
+mod config;
+mod logging;
+mod maintenance;
 mod p2p;
+#[cfg(feature = "profiling")]
+mod profiling;
+
+use config::Config;
 
 pub struct App {
     pub blocks: Vec<Block>,
+    // blocks which were slightly ahead of our clock when received
+    pub future_blocks: Vec<p2p::InboundBlock>,
+    pub metrics: Metrics,
+    // the config the node runs with, passed in so it can differ per instance
+    pub config: Config,
+}
+
+// counters since the node started
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Metrics {
+    pub blocks_mined: u64,
+    pub blocks_received: u64,
+    // how often the local chain was replaced by a received one
+    pub chains_adopted: u64,
+    // received blocks dropped because the inbound block queue was full
+    pub blocks_dropped: u64,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct Block {
     pub id: u64,
     pub hash: String,
@@ -35,166 +91,793 @@ pub struct Block {
     pub timestamp: i64,
     pub data: String,
     pub nonce: u64,
+    // work of the chain up to and including this block, see Config::include_total_work
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total_work: Option<u64>,
 }
 
 impl Block {
-    pub fn new(id: u64, previous_hash: String, data: String) -> Self {
-        let now = Utc::now();
-        let (nonce, hash) = mine_block(id, now.timestamp(), &previous_hash, &data);
-        Self {
+    // mines the block following previous, giving up once the deadline passed
+    pub fn next(
+        previous: &Block,
+        data: String,
+        deadline: Option<Instant>,
+        config: &Config,
+    ) -> Result<Self, MiningError> {
+        // a block has to be later than its predecessor, but peers reject blocks too far ahead of
+        // their clock
+        let now = Utc::now().timestamp();
+        let timestamp = now.max(previous.timestamp + 1);
+        if timestamp > now + MAX_FUTURE_DRIFT_SECS {
+            return Err(MiningError::AheadOfClock);
+        }
+        let difficulty_prefix = difficulty_prefix(timestamp, previous.timestamp, config);
+        let total_work = if config.include_total_work {
+            previous
+                .total_work(config)
+                .and_then(|total_work| total_work.checked_add(block_work(difficulty_prefix)))
+        } else {
+            None
+        };
+        let (block, record) = Self::mine(
+            previous.id + 1,
+            previous.hash.clone(),
+            data,
+            total_work,
+            timestamp,
+            difficulty_prefix,
+            deadline,
+        )?;
+        // only blocks the node mines are logged, not the ones maintenance commands mine
+        if let Some(path) = &config.mining_log_path {
+            if let Err(e) = append_mining_record(path, &record) {
+                error!("could not write mining record to {}, {}", path, e);
+            }
+        }
+        Ok(block)
+    }
+
+    pub fn with_timestamp(
+        id: u64,
+        previous_hash: String,
+        data: String,
+        total_work: Option<u64>,
+        timestamp: i64,
+    ) -> Self {
+        Self::mine(
             id,
-            hash,
-            timestamp: now.timestamp(),
             previous_hash,
             data,
-            nonce,
+            total_work,
+            timestamp,
+            DIFFICULTY_PREFIX,
+            None,
+        )
+        .expect("mining without a deadline doesn't time out")
+        .0
+    }
+
+    fn mine(
+        id: u64,
+        previous_hash: String,
+        data: String,
+        total_work: Option<u64>,
+        timestamp: i64,
+        difficulty_prefix: &str,
+        deadline: Option<Instant>,
+    ) -> Result<(Self, MiningRecord), MiningError> {
+        let record = mine_block(
+            id,
+            timestamp,
+            &previous_hash,
+            &data,
+            total_work,
+            difficulty_prefix,
+            deadline,
+        )?;
+        let block = Self {
+            id,
+            hash: record.hash.clone(),
+            timestamp,
+            previous_hash,
+            data,
+            nonce: record.nonce,
+            total_work,
+        };
+        Ok((block, record))
+    }
+
+    // The work of the chain up to and including this block, if it's known from the block alone.
+    // Without a total_work field, that's only the case if every block has the full difficulty,
+    // which isn't a given on a testnet - see App::chain_work for summing it up instead.
+    // None as well if it overflows
+    pub fn total_work(&self, config: &Config) -> Option<u64> {
+        match self.total_work {
+            Some(total_work) => Some(total_work),
+            None if self.id == 0 || !config.testnet => self
+                .id
+                .checked_add(1)
+                .and_then(|blocks| blocks.checked_mul(block_work(DIFFICULTY_PREFIX))),
+            None => None,
+        }
+    }
+
+    // the work of this block alone, at the difficulty it had to meet after previous
+    pub fn work(&self, previous: &Block, config: &Config) -> u64 {
+        block_work(difficulty_prefix(
+            self.timestamp,
+            previous.timestamp,
+            config,
+        ))
+    }
+
+    fn meets_difficulty(&self, difficulty_prefix: &str) -> bool {
+        hex::decode(&self.hash)
+            .map(|hash| hash_to_binary_representation(&hash).starts_with(difficulty_prefix))
+            .unwrap_or(false)
+    }
+
+    // the encoding of the block's contents which is hashed, see canonical_bytes
+    pub fn canonical_bytes(&self) -> Vec<u8> {
+        canonical_bytes(
+            self.id,
+            self.timestamp,
+            &self.previous_hash,
+            &self.data,
+            self.nonce,
+            self.total_work,
+        )
+    }
+
+    // the hash of the block's contents, which its stored hash has to match
+    pub fn recompute_hash(&self) -> String {
+        hex::encode(calculate_hash(
+            self.id,
+            self.timestamp,
+            &self.previous_hash,
+            &self.data,
+            self.nonce,
+            self.total_work,
+        ))
+    }
+
+    fn has_correct_hash(&self) -> bool {
+        self.recompute_hash() == self.hash
+    }
+
+    // the proof of work holds if the block hashes to its claimed hash, which meets the lowest
+    // difficulty any block may have - the Difficulty rule checks the one for its position
+    pub fn has_valid_pow(&self, config: &Config) -> bool {
+        self.meets_difficulty(min_difficulty_prefix(config)) && self.has_correct_hash()
+    }
+}
+
+// The rules a block has to pass to be appended to its previous block, in the order they're checked in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockRule {
+    PreviousHash,
+    Difficulty,
+    Id,
+    Hash,
+    Timestamp,
+    TotalWork,
+}
+
+impl BlockRule {
+    pub const ALL: [BlockRule; 6] = [
+        BlockRule::PreviousHash,
+        BlockRule::Difficulty,
+        BlockRule::Id,
+        BlockRule::Hash,
+        BlockRule::Timestamp,
+        BlockRule::TotalWork,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            BlockRule::PreviousHash => "previous hash",
+            BlockRule::Difficulty => "difficulty",
+            BlockRule::Id => "id",
+            BlockRule::Hash => "hash",
+            BlockRule::Timestamp => "timestamp",
+            BlockRule::TotalWork => "total work",
+        }
+    }
+
+    // now is the current time, which a block may be at most MAX_FUTURE_DRIFT_SECS ahead of
+    pub fn check(&self, block: &Block, previous_block: &Block, config: &Config, now: i64) -> bool {
+        match self {
+            BlockRule::PreviousHash => block.previous_hash == previous_block.hash,
+            BlockRule::Difficulty => block.meets_difficulty(difficulty_prefix(
+                block.timestamp,
+                previous_block.timestamp,
+                config,
+            )),
+            BlockRule::Id => block.id == previous_block.id + 1,
+            BlockRule::Hash => block.has_correct_hash(),
+            BlockRule::Timestamp => {
+                block.timestamp > previous_block.timestamp
+                    && block.timestamp <= now + MAX_FUTURE_DRIFT_SECS
+            }
+            BlockRule::TotalWork => block
+                .total_work
+                .map(|total_work| {
+                    previous_block
+                        .total_work(config)
+                        .and_then(|w| w.checked_add(block.work(previous_block, config)))
+                        == Some(total_work)
+                })
+                .unwrap_or(true),
+        }
+    }
+
+    // context-free rules only look at the block itself, not at its predecessor
+    pub fn is_context_free(&self) -> bool {
+        match self {
+            BlockRule::PreviousHash
+            | BlockRule::Difficulty
+            | BlockRule::Id
+            | BlockRule::Timestamp
+            | BlockRule::TotalWork => false,
+            BlockRule::Hash => true,
+        }
+    }
+
+    pub fn first_failed(
+        block: &Block,
+        previous_block: &Block,
+        config: &Config,
+        now: i64,
+    ) -> Option<BlockRule> {
+        Self::ALL
+            .iter()
+            .find(|rule| !rule.check(block, previous_block, config, now))
+            .copied()
+    }
+
+    // runs all rules, without stopping at the first failing one
+    pub fn trace(
+        block: &Block,
+        previous_block: &Block,
+        config: &Config,
+        now: i64,
+    ) -> Vec<(BlockRule, bool)> {
+        Self::ALL
+            .iter()
+            .map(|rule| (*rule, rule.check(block, previous_block, config, now)))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidChain {
+    Empty,
+    GenesisMismatch,
+    InvalidBlock(u64, BlockRule),
+}
+
+impl fmt::Display for InvalidChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidChain::Empty => write!(f, "chain is empty"),
+            InvalidChain::GenesisMismatch => write!(f, "chain has a different genesis block"),
+            InvalidChain::InvalidBlock(id, rule) => {
+                write!(f, "block with id: {} fails the {} rule", id, rule.name())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MiningError {
+    // no hash meeting the difficulty was found before the deadline
+    Timeout,
+    // the previous block is so far in the future that the next one would be rejected
+    AheadOfClock,
+}
+
+impl fmt::Display for MiningError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MiningError::Timeout => write!(f, "mining timed out"),
+            MiningError::AheadOfClock => {
+                write!(f, "the previous block is too far ahead of the clock")
+            }
         }
     }
 }
 
-fn calculate_hash(id: u64, timestamp: i64, previous_hash: &str, data: &str, nonce: u64) -> Vec<u8> {
-    let data = serde_json::json!({
+fn calculate_hash(
+    id: u64,
+    timestamp: i64,
+    previous_hash: &str,
+    data: &str,
+    nonce: u64,
+    total_work: Option<u64>,
+) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(canonical_bytes(
+        id,
+        timestamp,
+        previous_hash,
+        data,
+        nonce,
+        total_work,
+    ));
+    hasher.finalize().to_vec()
+}
+
+// The bytes a block's hash is the SHA-256 of: its fields as compact JSON, with the keys in
+// lexicographic order and without whitespace
+fn canonical_bytes(
+    id: u64,
+    timestamp: i64,
+    previous_hash: &str,
+    data: &str,
+    nonce: u64,
+    total_work: Option<u64>,
+) -> Vec<u8> {
+    let mut data = serde_json::json!({
         "id": id,
         "previous_hash": previous_hash,
         "data": data,
         "timestamp": timestamp,
         "nonce": nonce
     });
-    let mut hasher = Sha256::new();
-    hasher.update(data.to_string().as_bytes());
-    hasher.finalize().as_slice().to_owned()
+    // only hashed if present, so blocks without it keep their hash
+    if let Some(total_work) = total_work {
+        data["total_work"] = serde_json::json!(total_work);
+    }
+    data.to_string().into_bytes()
 }
 
-fn mine_block(id: u64, timestamp: i64, previous_hash: &str, data: &str) -> (u64, String) {
+fn mine_block(
+    id: u64,
+    timestamp: i64,
+    previous_hash: &str,
+    data: &str,
+    total_work: Option<u64>,
+    difficulty_prefix: &str,
+    deadline: Option<Instant>,
+) -> Result<MiningRecord, MiningError> {
     info!("mining block...");
+    #[cfg(feature = "profiling")]
+    let profiler = profiling::start();
+    let started = Instant::now();
     let mut nonce = 0;
 
     loop {
         if nonce % 100000 == 0 {
             info!("nonce: {}", nonce);
+            if nonce > 0 {
+                record_hashrate(nonce as f64 / started.elapsed().as_secs_f64());
+            }
+            if deadline
+                .map(|deadline| Instant::now() >= deadline)
+                .unwrap_or(false)
+            {
+                return Err(MiningError::Timeout);
+            }
         }
-        let hash = calculate_hash(id, timestamp, previous_hash, data, nonce);
+        let hash = calculate_hash(id, timestamp, previous_hash, data, nonce, total_work);
         let binary_hash = hash_to_binary_representation(&hash);
-        if binary_hash.starts_with(DIFFICULTY_PREFIX) {
+        if binary_hash.starts_with(difficulty_prefix) {
             info!(
                 "mined! nonce: {}, hash: {}, binary hash: {}",
                 nonce,
                 hex::encode(&hash),
                 binary_hash
             );
-            return (nonce, hex::encode(hash));
+            #[cfg(feature = "profiling")]
+            profiling::write_flamegraph(profiler, id);
+            let duration = started.elapsed();
+            let hashrate = (nonce + 1) as f64 / duration.as_secs_f64();
+            *LAST_HASHRATE.lock().expect("can lock hashrate") = Some(hashrate);
+            record_hashrate(hashrate);
+            return Ok(MiningRecord {
+                id,
+                nonce,
+                hash: hex::encode(&hash),
+                attempts: nonce + 1,
+                duration_ms: duration.as_millis(),
+                hashrate,
+            });
         }
         nonce += 1;
     }
 }
 
+fn record_hashrate(hashrate: f64) {
+    let mut history = HASHRATE_HISTORY.lock().expect("can lock hashrate history");
+    history.push_back(HashrateSample {
+        timestamp: Utc::now().timestamp(),
+        hashrate,
+    });
+    while history.len() > config::get().max_hashrate_samples {
+        history.pop_front();
+    }
+}
+
+// the work a single block represents, at the given difficulty
+fn block_work(difficulty_prefix: &str) -> u64 {
+    1 << difficulty_prefix.len()
+}
+
+// the difficulty a block mined at timestamp has to meet, after a block mined at previous_timestamp
+fn difficulty_prefix(timestamp: i64, previous_timestamp: i64, config: &Config) -> &'static str {
+    if config.testnet && timestamp >= previous_timestamp + TESTNET_RESET_TIMEOUT_SECS {
+        MIN_DIFFICULTY_PREFIX
+    } else {
+        DIFFICULTY_PREFIX
+    }
+}
+
+// the lowest difficulty any block may have
+fn min_difficulty_prefix(config: &Config) -> &'static str {
+    if config.testnet {
+        MIN_DIFFICULTY_PREFIX
+    } else {
+        DIFFICULTY_PREFIX
+    }
+}
+
+// expected number of hashes needed to find one with the given number of leading zero bits
+fn expected_hashes(difficulty_bits: usize) -> f64 {
+    2f64.powi(difficulty_bits as i32)
+}
+
+// one line of the mining log, see Config::mining_log_path
+#[derive(Serialize, Debug, PartialEq)]
+struct MiningRecord {
+    id: u64,
+    nonce: u64,
+    hash: String,
+    attempts: u64,
+    duration_ms: u128,
+    hashrate: f64,
+}
+
+fn append_mining_record(path: &str, record: &MiningRecord) -> std::io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    let json = serde_json::to_string(record).expect("can jsonify mining record");
+    writeln!(file, "{}", json)
+}
+
 fn hash_to_binary_representation(hash: &[u8]) -> String {
     let mut res: String = String::default();
     for c in hash {
-        res.push_str(&format!("{:b}", c));
+        res.push_str(&format!("{:08b}", c));
     }
     res
 }
 
+// how many of a hash's bits, in its binary representation, are zero before the first one
+fn leading_zero_bits(binary_hash: &str) -> usize {
+    binary_hash.chars().take_while(|c| *c == '0').count()
+}
+
+// whether the chain starts with exactly our genesis block, so the work it claims can be trusted
+fn starts_at_genesis(chain: &[Block]) -> bool {
+    chain.first() == Some(&genesis_block())
+}
+
+fn genesis_block() -> Block {
+    Block {
+        id: 0,
+        timestamp: GENESIS_TIMESTAMP,
+        previous_hash: String::from("genesis"),
+        data: String::from("genesis!"),
+        nonce: GENESIS_NONCE,
+        hash: GENESIS_HASH.to_string(),
+        total_work: None,
+    }
+}
+
+// Checks that the genesis block hashes to the configured network id, if there is one
+fn verify_network_id(network_id: Option<&str>) -> Result<(), String> {
+    let network_id = match network_id {
+        Some(network_id) => network_id,
+        None => return Ok(()),
+    };
+    let genesis = genesis_block();
+    let genesis_hash = hex::encode(calculate_hash(
+        genesis.id,
+        genesis.timestamp,
+        &genesis.previous_hash,
+        &genesis.data,
+        genesis.nonce,
+        genesis.total_work,
+    ));
+    if genesis_hash != network_id {
+        return Err(format!(
+            "genesis block hashes to {}, which doesn't match the network id {}",
+            genesis_hash, network_id
+        ));
+    }
+    Ok(())
+}
+
+// Checks that the genesis block meets the difficulty, which it won't after the difficulty was raised
+fn verify_genesis_difficulty(genesis: &Block, difficulty_prefix: &str) -> Result<(), String> {
+    if genesis.meets_difficulty(difficulty_prefix) {
+        return Ok(());
+    }
+    let zero_bits = hex::decode(&genesis.hash)
+        .map(|hash| leading_zero_bits(&hash_to_binary_representation(&hash)))
+        .unwrap_or(0);
+    Err(format!(
+        "the genesis block's hash has {} leading zero bits, but the difficulty requires {} - \
+         re-mine it with `cargo run -- maintenance fix-genesis`",
+        zero_bits,
+        difficulty_prefix.len()
+    ))
+}
+
 impl App {
-    fn new() -> Self {
-        Self { blocks: vec![] }
+    fn new(config: Config) -> Self {
+        Self {
+            blocks: vec![],
+            future_blocks: vec![],
+            metrics: Metrics::default(),
+            config,
+        }
     }
 
     fn genesis(&mut self) {
-        let genesis_block = Block {
-            id: 0,
-            timestamp: Utc::now().timestamp(),
-            previous_hash: String::from("genesis"),
-            data: String::from("genesis!"),
-            nonce: 2836,
-            hash: "0000f816a87f806bb0073dcf026a64fb40c946b5abee2573702828694d5b4c43".to_string(),
-        };
-        self.blocks.push(genesis_block);
+        self.blocks.push(genesis_block());
     }
 
-    fn try_add_block(&mut self, block: Block) {
+    // adds the block source sent to the chain, or queues it if it's slightly ahead of now,
+    // returns the rule it failed if it's invalid
+    fn try_add_block(&mut self, block: Block, source: PeerId, now: i64) -> Result<(), BlockRule> {
+        let drift = block.timestamp - now;
+        if drift > MAX_FUTURE_DRIFT_SECS && drift <= self.config.future_block_grace_window_secs {
+            if self.future_blocks.len() >= self.config.max_queued_future_blocks {
+                warn!(
+                    "future block queue is full, dropping block with id: {}",
+                    block.id
+                );
+            } else {
+                info!(
+                    "block with id: {} is {}s in the future, queueing it",
+                    block.id, drift
+                );
+                self.future_blocks.push(p2p::InboundBlock { source, block });
+            }
+            return Ok(());
+        }
         let latest_block = self.blocks.last().expect("there is at least one block");
-        if self.is_block_valid(&block, latest_block) {
-            self.blocks.push(block);
-        } else {
-            error!("could not add block - invalid");
+        match BlockRule::first_failed(&block, latest_block, &self.config, now) {
+            None => {
+                self.blocks.push(block);
+                self.metrics.blocks_received += 1;
+                Ok(())
+            }
+            Some(rule) => {
+                Self::warn_invalid_block(&block, latest_block, rule);
+                error!("could not add block - invalid");
+                Err(rule)
+            }
         }
     }
 
-    fn is_block_valid(&self, block: &Block, previous_block: &Block) -> bool {
-        if block.previous_hash != previous_block.hash {
-            warn!("block with id: {} has wrong previous hash", block.id);
-            return false;
-        } else if !hash_to_binary_representation(
-            &hex::decode(&block.hash).expect("can decode from hex"),
-        )
-        .starts_with(DIFFICULTY_PREFIX)
-        {
-            warn!("block with id: {} has invalid difficulty", block.id);
-            return false;
-        } else if block.id != previous_block.id + 1 {
-            warn!(
+    // re-tries queued future blocks, which are within MAX_FUTURE_DRIFT_SECS of now by now,
+    // returns the ones which turned out to be invalid, with the rule they failed
+    fn retry_future_blocks(&mut self, now: i64) -> Vec<(p2p::InboundBlock, BlockRule)> {
+        let (mut ready, waiting): (Vec<_>, Vec<_>) = std::mem::take(&mut self.future_blocks)
+            .into_iter()
+            .partition(|inbound| inbound.block.timestamp <= now + MAX_FUTURE_DRIFT_SECS);
+        self.future_blocks = waiting;
+        ready.sort_by_key(|inbound| inbound.block.id);
+        let mut rejected = vec![];
+        for inbound in ready {
+            if let Err(rule) = self.try_add_block(inbound.block.clone(), inbound.source, now) {
+                rejected.push((inbound, rule));
+            }
+        }
+        rejected
+    }
+
+    fn is_block_valid(block: &Block, previous_block: &Block, config: &Config, now: i64) -> bool {
+        match BlockRule::first_failed(block, previous_block, config, now) {
+            None => true,
+            Some(rule) => {
+                Self::warn_invalid_block(block, previous_block, rule);
+                false
+            }
+        }
+    }
+
+    fn warn_invalid_block(block: &Block, previous_block: &Block, rule: BlockRule) {
+        match rule {
+            BlockRule::PreviousHash => {
+                warn!("block with id: {} has wrong previous hash", block.id)
+            }
+            BlockRule::Difficulty => warn!("block with id: {} has invalid difficulty", block.id),
+            BlockRule::Id => warn!(
                 "block with id: {} is not the next block after the latest: {}",
                 block.id, previous_block.id
-            );
-            return false;
-        } else if hex::encode(calculate_hash(
-            block.id,
-            block.timestamp,
-            &block.previous_hash,
-            &block.data,
-            block.nonce,
-        )) != block.hash
-        {
-            warn!("block with id: {} has invalid hash", block.id);
-            return false;
+            ),
+            BlockRule::Hash => warn!("block with id: {} has invalid hash", block.id),
+            BlockRule::Timestamp => {
+                warn!(
+                    "block with id: {} is too far in the future, or not later than the latest",
+                    block.id
+                )
+            }
+            BlockRule::TotalWork => {
+                warn!("block with id: {} has inconsistent total work", block.id)
+            }
         }
-        true
     }
 
-    fn is_chain_valid(&self, chain: &[Block]) -> bool {
-        for i in 0..chain.len() {
-            if i == 0 {
-                continue;
+    // Fully verifies a chain from genesis on, without logging, reporting the first problem found
+    fn verify_chain(chain: &[Block], config: &Config, now: i64) -> Result<(), InvalidChain> {
+        if chain.is_empty() {
+            return Err(InvalidChain::Empty);
+        }
+        if !starts_at_genesis(chain) {
+            return Err(InvalidChain::GenesisMismatch);
+        }
+        for pair in chain.windows(2) {
+            if let Some(rule) = BlockRule::first_failed(&pair[1], &pair[0], config, now) {
+                return Err(InvalidChain::InvalidBlock(pair[1].id, rule));
+            }
+        }
+        Ok(())
+    }
+
+    // Checks the context-free rules (the hash) of all blocks in parallel,
+    // and only the rules linking a block to its predecessor sequentially
+    fn is_chain_valid_parallel(chain: &[Block], config: &Config, now: i64) -> bool {
+        if !starts_at_genesis(chain) {
+            warn!("received chain doesn't start with our genesis block");
+            return false;
+        }
+        let first_failed = |i: usize, context_free: bool| {
+            BlockRule::ALL
+                .iter()
+                .filter(|rule| rule.is_context_free() == context_free)
+                .find(|rule| !rule.check(&chain[i], &chain[i - 1], config, now))
+                .map(|rule| (i, *rule))
+        };
+        let failed = (1..chain.len())
+            .into_par_iter()
+            .find_map_first(|i| first_failed(i, true))
+            .or_else(|| (1..chain.len()).find_map(|i| first_failed(i, false)));
+        match failed {
+            None => true,
+            Some((i, rule)) => {
+                Self::warn_invalid_block(&chain[i], &chain[i - 1], rule);
+                false
             }
+        }
+    }
+
+    // Validates a received chain, yielding to the runtime after every chunk of blocks,
+    // so validating a long chain doesn't starve stdin and p2p handling
+    async fn is_chain_valid_chunked(chain: &[Block], config: &Config, now: i64) -> bool {
+        if config.parallel_chain_validation {
+            // rayon blocks the calling thread until all blocks are verified
+            return block_in_place(|| Self::is_chain_valid_parallel(chain, config, now));
+        }
+        if !starts_at_genesis(chain) {
+            warn!("received chain doesn't start with our genesis block");
+            return false;
+        }
+        for i in 1..chain.len() {
             let first = chain.get(i - 1).expect("has to exist");
             let second = chain.get(i).expect("has to exist");
-            if !self.is_block_valid(second, first) {
+            if !Self::is_block_valid(second, first, config, now) {
                 return false;
             }
+            if i % config.chain_validation_chunk_size == 0 {
+                yield_now().await;
+            }
         }
         true
     }
 
-    // We always choose the longest valid chain
-    fn choose_chain(&mut self, local: Vec<Block>, remote: Vec<Block>) -> Vec<Block> {
-        let is_local_valid = self.is_chain_valid(&local);
-        let is_remote_valid = self.is_chain_valid(&remote);
+    // the work of a chain is the total work of its tip, or the sum of its blocks' work if the tip
+    // doesn't carry it - None if that overflows
+    fn chain_work(chain: &[Block], config: &Config) -> Option<u64> {
+        match chain.last() {
+            Some(Block {
+                total_work: Some(total_work),
+                ..
+            }) => Some(*total_work),
+            _ => {
+                let genesis_work = match chain.first() {
+                    Some(genesis) => genesis.total_work(config)?,
+                    None => 0,
+                };
+                chain.windows(2).try_fold(genesis_work, |work, pair| {
+                    work.checked_add(pair[1].work(&pair[0], config))
+                })
+            }
+        }
+    }
 
-        if is_local_valid && is_remote_valid {
-            if local.len() >= remote.len() {
-                local
-            } else {
-                remote
+    // We always choose the heaviest valid chain
+    // The remote chain is validated beforehand (see is_chain_valid_chunked), since it can be long
+    fn choose_chain(
+        &mut self,
+        local: Vec<Block>,
+        remote: Vec<Block>,
+        is_remote_valid: bool,
+    ) -> Vec<Block> {
+        // the claimed tip hash has to match the tip block, whatever the validation said
+        let is_remote_valid = is_remote_valid
+            && match remote.last() {
+                Some(tip) if !tip.has_correct_hash() => {
+                    warn!(
+                        "received chain's tip with id: {} doesn't hash to {}",
+                        tip.id, tip.hash
+                    );
+                    false
+                }
+                _ => true,
+            };
+        // a chain ending in our tip is the same chain, there's nothing to adopt
+        if is_remote_valid && local.last().map(|b| &b.hash) == remote.last().map(|b| &b.hash) {
+            if local.len() != remote.len() {
+                warn!("received chain has our tip hash, but a different length - keeping ours");
             }
-        } else if is_remote_valid && !is_local_valid {
+            return local;
+        }
+        // the local chain isn't validated again, its blocks were validated when they were added
+        let is_remote_heavier = match (
+            Self::chain_work(&remote, &self.config),
+            Self::chain_work(&local, &self.config),
+        ) {
+            (Some(remote_work), Some(local_work)) => remote_work > local_work,
+            _ => false,
+        };
+        if is_remote_valid && is_remote_heavier {
             remote
-        } else if !is_remote_valid && is_local_valid {
-            local
         } else {
-            panic!("local and remote chains are both invalid");
+            local
         }
     }
 }
 
 #[tokio::main]
 async fn main() {
-    pretty_env_logger::init();
+    logging::init();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args = match config::load(&args) {
+        Ok((config, args)) => {
+            config::init(config);
+            args
+        }
+        Err(e) => {
+            error!("invalid config: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if args.first().map(String::as_str) == Some("maintenance") {
+        std::process::exit(maintenance::run(&args[1..]));
+    }
+    if args == ["config", "show"] {
+        print!("{}", config::show(config::get()));
+        return;
+    }
+
+    if let Err(e) = verify_network_id(config::get().network_id.as_deref())
+        .and_then(|_| verify_genesis_difficulty(&genesis_block(), DIFFICULTY_PREFIX))
+    {
+        error!("refusing to start: {}", e);
+        std::process::exit(1);
+    }
 
     info!("Peer Id: {}", p2p::PEER_ID.clone());
-    let (response_sender, mut response_rcv) = mpsc::unbounded_channel();
+    let (response_sender, mut response_rcv) =
+        mpsc::channel(config::get().response_channel_capacity);
     let (init_sender, mut init_rcv) = mpsc::unbounded_channel();
+    let (validated_sender, mut validated_rcv) = mpsc::unbounded_channel();
+    let (block_sender, mut block_rcv) = mpsc::channel(config::get().inbound_block_queue_capacity);
+    let (mined_sender, mut mined_rcv) = mpsc::unbounded_channel();
 
     let auth_keys = Keypair::<X25519Spec>::new()
         .into_authentic(&p2p::KEYS)
@@ -206,19 +889,34 @@ async fn main() {
         .multiplex(mplex::MplexConfig::new())
         .boxed();
 
-    let behaviour = p2p::AppBehaviour::new(App::new(), response_sender, init_sender.clone()).await;
+    let behaviour = p2p::AppBehaviour::new(
+        App::new(config::get().clone()),
+        response_sender,
+        validated_sender,
+        block_sender,
+        mined_sender,
+    )
+    .await;
+
+    // connections beyond the limits are refused when they're established, per direction -
+    // the total is enforced once a connection is established, see p2p::handle_connection_established
+    let limits = ConnectionLimits::default()
+        .with_max_established_incoming(Some(config::get().max_peers))
+        .with_max_established_outgoing(Some(config::get().max_peers));
 
     let mut swarm = SwarmBuilder::new(transp, behaviour, *p2p::PEER_ID)
+        .connection_limits(limits)
         .executor(Box::new(|fut| {
             spawn(fut);
         }))
         .build();
 
     let mut stdin = BufReader::new(stdin()).lines();
+    let mut future_blocks_interval = interval(Duration::from_secs(1));
 
     Swarm::listen_on(
         &mut swarm,
-        "/ip4/0.0.0.0/tcp/0"
+        format!("/ip4/0.0.0.0/tcp/{}", config::get().listen_port)
             .parse()
             .expect("can get a local socket"),
     )
@@ -237,38 +935,45 @@ async fn main() {
                 response = response_rcv.recv() => {
                     Some(p2p::EventType::LocalChainResponse(response.expect("response exists")))
                 },
-                _init = init_rcv.recv() => {
+                Some(_init) = init_rcv.recv() => {
                     Some(p2p::EventType::Init)
                 }
-                event = swarm.select_next_some() => {
-                    info!("Unhandled Swarm Event: {:?}", event);
-                    None
+                mined = mined_rcv.recv() => {
+                    Some(p2p::EventType::MinedBlock(mined.expect("mined block exists")))
+                },
+                inbound = block_rcv.recv() => {
+                    Some(p2p::EventType::InboundBlock(inbound.expect("inbound block exists")))
+                },
+                validated = validated_rcv.recv() => {
+                    Some(p2p::EventType::ValidatedChain(validated.expect("validated chain exists")))
+                },
+                _tick = future_blocks_interval.tick() => {
+                    Some(p2p::EventType::RetryFutureBlocks)
+                },
+                event = swarm.select_next_some() => match event {
+                    SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                        Some(p2p::EventType::ConnectionEstablished(peer_id))
+                    }
+                    event => {
+                        info!("Unhandled Swarm Event: {:?}", event);
+                        None
+                    }
                 },
             }
         };
 
         if let Some(event) = evt {
             match event {
+                p2p::EventType::ConnectionEstablished(peer_id) => {
+                    p2p::handle_connection_established(peer_id, &mut swarm);
+                }
                 p2p::EventType::Init => {
-                    let peers = p2p::get_list_peers(&swarm);
+                    let peers = p2p::sync_peers(&swarm);
                     swarm.behaviour_mut().app.genesis();
 
                     info!("connected nodes: {}", peers.len());
-                    if !peers.is_empty() {
-                        let req = p2p::LocalChainRequest {
-                            from_peer_id: peers
-                                .iter()
-                                .last()
-                                .expect("at least one peer")
-                                .to_string(),
-                        };
-
-                        let json = serde_json::to_string(&req).expect("can jsonify request");
-                        swarm
-                            .behaviour_mut()
-                            .floodsub
-                            .publish(p2p::CHAIN_TOPIC.clone(), json.as_bytes());
-                    }
+                    swarm.behaviour_mut().sync_candidates = peers;
+                    p2p::request_chain(&mut swarm);
                 }
                 p2p::EventType::LocalChainResponse(resp) => {
                     let json = serde_json::to_string(&resp).expect("can jsonify response");
@@ -277,13 +982,569 @@ async fn main() {
                         .floodsub
                         .publish(p2p::CHAIN_TOPIC.clone(), json.as_bytes());
                 }
+                p2p::EventType::ValidatedChain(chain) => {
+                    let app = &mut swarm.behaviour_mut().app;
+                    let local_tip = app.blocks.last().map(|b| b.hash.clone());
+                    app.blocks = app.choose_chain(app.blocks.clone(), chain.blocks, chain.is_valid);
+                    if app.blocks.last().map(|b| b.hash.clone()) != local_tip {
+                        app.metrics.chains_adopted += 1;
+                    }
+                    if chain.is_valid {
+                        swarm.behaviour_mut().sync_candidates.clear();
+                    } else {
+                        // fall back to the next peer, if we're still syncing
+                        p2p::request_chain(&mut swarm);
+                    }
+                }
+                p2p::EventType::MinedBlock(mined) => {
+                    p2p::handle_mined_block(mined, &mut swarm);
+                }
+                p2p::EventType::InboundBlock(inbound) => {
+                    p2p::handle_inbound_block(inbound, &mut swarm);
+                }
+                p2p::EventType::RetryFutureBlocks => {
+                    p2p::handle_retry_future_blocks(&mut swarm);
+                    p2p::retry_chain_request(&mut swarm);
+                }
                 p2p::EventType::Input(line) => match line.as_str() {
                     "ls p" => p2p::handle_print_peers(&swarm),
+                    cmd if cmd.starts_with("ping") => p2p::handle_ping(cmd, &swarm),
+                    "export p" => p2p::handle_export_peers(&mut swarm),
+                    "memstats" => p2p::handle_print_memstats(&swarm),
+                    "metrics" => p2p::handle_print_metrics(&swarm),
+                    "hashrate" => p2p::handle_print_hashrate_history(),
+                    "difficulty" => p2p::handle_print_difficulty(),
+                    cmd if cmd.starts_with("simulate difficulty") => {
+                        p2p::handle_simulate_difficulty(cmd)
+                    }
+                    "config show" => p2p::handle_show_config(),
+                    cmd if cmd.starts_with("loglevel") => p2p::handle_set_log_level(cmd),
+                    cmd if cmd.starts_with("verify f") => p2p::handle_verify_file(cmd),
                     cmd if cmd.starts_with("ls c") => p2p::handle_print_chain(&swarm),
                     cmd if cmd.starts_with("create b") => p2p::handle_create_block(cmd, &mut swarm),
+                    cmd if cmd.starts_with("explain b") => p2p::handle_explain_block(cmd, &swarm),
+                    cmd if cmd.starts_with("dump b") => p2p::handle_dump_block(cmd, &swarm),
+                    cmd if cmd.starts_with("explain h") => p2p::handle_explain_hash(cmd, &swarm),
+                    "audit hashes" => p2p::handle_audit_hashes(&swarm),
+                    "attest" => p2p::handle_attest(&swarm),
+                    #[cfg(debug_assertions)]
+                    cmd if cmd.starts_with("partition") => p2p::handle_partition(cmd, &mut swarm),
+                    #[cfg(debug_assertions)]
+                    "heal" => p2p::handle_heal(&mut swarm),
                     _ => error!("unknown command"),
                 },
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    // a valid chain of genesis and two mined blocks, shared since mining takes a while
+    pub(crate) static CHAIN: Lazy<Vec<Block>> = Lazy::new(|| {
+        let mut chain = vec![genesis_block()];
+        for id in 1..=2 {
+            let previous = chain.last().expect("there is at least one block");
+            let block = Block::with_timestamp(
+                id,
+                previous.hash.clone(),
+                format!("block {}", id),
+                None,
+                previous.timestamp + 10,
+            );
+            chain.push(block);
+        }
+        chain
+    });
+
+    // a chain long enough for validating it to take a while, mined at the minimum difficulty of
+    // the testnet by spacing its blocks TESTNET_RESET_TIMEOUT_SECS apart
+    static LONG_TESTNET_CHAIN: Lazy<Vec<Block>> = Lazy::new(|| {
+        let mut chain = vec![genesis_block()];
+        for id in 1..=5000 {
+            let previous = chain.last().expect("there is at least one block");
+            let block = Block::mine(
+                id,
+                previous.hash.clone(),
+                format!("block {}", id),
+                None,
+                previous.timestamp + TESTNET_RESET_TIMEOUT_SECS,
+                MIN_DIFFICULTY_PREFIX,
+                None,
+            )
+            .expect("mining without a deadline doesn't time out")
+            .0;
+            chain.push(block);
+        }
+        chain
+    });
+
+    // the chain with the data of the block with the given id changed, so it no longer hashes
+    pub(crate) fn tampered_chain(id: usize) -> Vec<Block> {
+        let mut chain = CHAIN.clone();
+        chain[id].data = "tampered".to_string();
+        chain
+    }
+
+    #[test]
+    fn parallel_validation_agrees_with_sequential() {
+        let config = Config::default();
+        let now = Utc::now().timestamp();
+        let mut reordered = CHAIN.clone();
+        reordered.swap(1, 2);
+        let chains = [
+            CHAIN.clone(),
+            tampered_chain(1),
+            tampered_chain(2),
+            reordered,
+            CHAIN[..1].to_vec(),
+        ];
+        for chain in chains.iter() {
+            assert_eq!(
+                App::verify_chain(chain, &config, now).is_ok(),
+                App::is_chain_valid_parallel(chain, &config, now)
+            );
+        }
+        assert!(App::is_chain_valid_parallel(&CHAIN, &config, now));
+        assert!(!App::is_chain_valid_parallel(
+            &tampered_chain(2),
+            &config,
+            now
+        ));
+    }
+
+    // block_in_place, which parallel validation runs in, needs the multi-threaded runtime
+    #[tokio::test(flavor = "multi_thread")]
+    async fn chunked_validation_agrees_with_sequential() {
+        let config = Config::default();
+        let now = Utc::now().timestamp();
+        for chain in [CHAIN.clone(), tampered_chain(1), tampered_chain(2)].iter() {
+            assert_eq!(
+                App::verify_chain(chain, &config, now).is_ok(),
+                App::is_chain_valid_chunked(chain, &config, now).await
+            );
+        }
+    }
+
+    // without parallel validation, the chain is validated on the runtime's thread, which other
+    // tasks get to run on after every chunk of blocks
+    #[tokio::test]
+    async fn chunked_validation_yields_to_other_tasks() {
+        let config = Config {
+            testnet: true,
+            parallel_chain_validation: false,
+            chain_validation_chunk_size: 100,
+            ..Config::default()
+        };
+        let chain = &*LONG_TESTNET_CHAIN;
+        let now = Utc::now().timestamp();
+
+        let validating = Arc::new(AtomicBool::new(true));
+        // measures the longest it didn't get to run while the chain is validated
+        let ticker = tokio::spawn({
+            let validating = validating.clone();
+            async move {
+                let mut ticks = 0;
+                let mut longest_gap = Duration::default();
+                let mut last_tick = Instant::now();
+                while validating.load(Ordering::SeqCst) {
+                    yield_now().await;
+                    ticks += 1;
+                    longest_gap = longest_gap.max(last_tick.elapsed());
+                    last_tick = Instant::now();
+                }
+                (ticks, longest_gap)
+            }
+        });
+
+        let started = Instant::now();
+        let valid = App::is_chain_valid_chunked(chain, &config, now).await;
+        let validation = started.elapsed();
+        validating.store(false, Ordering::SeqCst);
+        let (ticks, longest_gap) = ticker.await.expect("ticker doesn't panic");
+
+        assert!(valid);
+        assert!(ticks >= 10, "the ticker only ran {} times", ticks);
+        assert!(
+            longest_gap < validation / 10,
+            "the ticker was blocked for {:?} of {:?}",
+            longest_gap,
+            validation
+        );
+    }
+
+    #[test]
+    fn trace_only_fails_the_broken_rule() {
+        let chain = tampered_chain(2);
+        let trace = BlockRule::trace(
+            &chain[2],
+            &chain[1],
+            &Config::default(),
+            Utc::now().timestamp(),
+        );
+        assert_eq!(trace.len(), BlockRule::ALL.len());
+        for (rule, passed) in trace {
+            assert_eq!(passed, rule != BlockRule::Hash, "{} rule", rule.name());
+        }
+    }
+
+    #[test]
+    fn future_blocks_are_added_once_the_clock_catches_up() {
+        let mut app = App::new(Config::default());
+        app.genesis();
+        let block = CHAIN[1].clone();
+        // a clock behind the block by more than the drift allows, but within the grace window
+        let now = block.timestamp - MAX_FUTURE_DRIFT_SECS - 10;
+        assert_eq!(
+            app.try_add_block(block.clone(), PeerId::random(), now),
+            Ok(())
+        );
+        assert_eq!(app.future_blocks.len(), 1);
+        assert_eq!(app.blocks.len(), 1);
+
+        assert!(app.retry_future_blocks(now + 5).is_empty());
+        assert_eq!(app.future_blocks.len(), 1);
+        assert_eq!(app.blocks.len(), 1);
+
+        assert!(app.retry_future_blocks(now + 10).is_empty());
+        assert!(app.future_blocks.is_empty());
+        assert_eq!(app.blocks.last().map(|b| &b.hash), Some(&block.hash));
+    }
+
+    #[test]
+    fn invalid_future_blocks_are_returned_with_their_source() {
+        let mut app = App::new(Config::default());
+        app.genesis();
+        let block = tampered_chain(1)[1].clone();
+        let source = PeerId::random();
+        let now = block.timestamp - MAX_FUTURE_DRIFT_SECS - 10;
+        assert_eq!(app.try_add_block(block.clone(), source, now), Ok(()));
+
+        let rejected = app.retry_future_blocks(now + 10);
+        assert_eq!(rejected.len(), 1);
+        let (inbound, rule) = &rejected[0];
+        assert_eq!(
+            (inbound.source, &inbound.block, *rule),
+            (source, &block, BlockRule::Hash)
+        );
+        assert!(app.future_blocks.is_empty());
+        assert_eq!(app.blocks.len(), 1);
+    }
+
+    #[test]
+    fn future_blocks_beyond_the_grace_window_are_rejected() {
+        let mut app = App::new(Config::default());
+        app.genesis();
+        let block = CHAIN[1].clone();
+        let now = block.timestamp - app.config.future_block_grace_window_secs - 1;
+        assert_eq!(
+            app.try_add_block(block, PeerId::random(), now),
+            Err(BlockRule::Timestamp)
+        );
+        assert!(app.future_blocks.is_empty());
+        assert_eq!(app.blocks.len(), 1);
+    }
+
+    #[test]
+    fn total_work_has_to_extend_the_previous_block() {
+        let config = Config::default();
+        let now = Utc::now().timestamp();
+        let genesis = genesis_block();
+        let mut block = CHAIN[1].clone();
+        // blocks without it are fine
+        assert!(BlockRule::TotalWork.check(&block, &genesis, &config, now));
+
+        let total_work = genesis
+            .total_work(&config)
+            .expect("genesis has a total work")
+            + block.work(&genesis, &config);
+        block.total_work = Some(total_work);
+        assert!(BlockRule::TotalWork.check(&block, &genesis, &config, now));
+        block.total_work = Some(total_work + 1);
+        assert!(!BlockRule::TotalWork.check(&block, &genesis, &config, now));
+    }
+
+    #[test]
+    fn chain_work_prefers_the_tips_total_work() {
+        assert_eq!(
+            App::chain_work(&CHAIN, &Config::default()),
+            Some(3 * block_work(DIFFICULTY_PREFIX))
+        );
+        let mut chain = CHAIN.clone();
+        chain[2].total_work = Some(42);
+        assert_eq!(App::chain_work(&chain, &Config::default()), Some(42));
+    }
+
+    #[test]
+    fn chains_not_starting_at_genesis_are_rejected() {
+        let config = Config::default();
+        let now = Utc::now().timestamp();
+        // a single block claiming a huge height, which hashes correctly but was never mined
+        let mut block = genesis_block();
+        block.id = (1 << 48) - 2;
+        block.hash = block.recompute_hash();
+        let remote = vec![block];
+        assert_eq!(
+            App::verify_chain(&remote, &config, now),
+            Err(InvalidChain::GenesisMismatch)
+        );
+        let is_valid = App::is_chain_valid_parallel(&remote, &config, now);
+        assert!(!is_valid);
+        let mut app = App::new(config);
+        let chosen = app.choose_chain(CHAIN.clone(), remote, is_valid);
+        assert_eq!(chosen, *CHAIN);
+    }
+
+    #[tokio::test]
+    async fn chains_with_a_forged_genesis_work_are_rejected() {
+        let config = Config {
+            parallel_chain_validation: false,
+            ..Config::default()
+        };
+        let now = Utc::now().timestamp();
+        let mut genesis = genesis_block();
+        genesis.total_work = Some(1 << 62);
+        let total_work = (1 << 62) + block_work(DIFFICULTY_PREFIX);
+        let block = Block::with_timestamp(
+            1,
+            genesis.hash.clone(),
+            "forged".to_string(),
+            Some(total_work),
+            genesis.timestamp + 10,
+        );
+        // the block is consistent with the forged genesis, only the genesis gives it away
+        assert_eq!(
+            BlockRule::first_failed(&block, &genesis, &config, now),
+            None
+        );
+        let remote = vec![genesis, block];
+        assert_eq!(
+            App::verify_chain(&remote, &config, now),
+            Err(InvalidChain::GenesisMismatch)
+        );
+        assert!(!App::is_chain_valid_parallel(&remote, &config, now));
+        let is_valid = App::is_chain_valid_chunked(&remote, &config, now).await;
+        assert!(!is_valid);
+        let mut app = App::new(config);
+        let chosen = app.choose_chain(CHAIN.clone(), remote, is_valid);
+        assert_eq!(chosen, *CHAIN);
+    }
+
+    #[test]
+    fn overflowing_work_is_never_heavier() {
+        let config = Config::default();
+        let mut block = genesis_block();
+        block.id = u64::MAX / 2;
+        block.hash = block.recompute_hash();
+        assert_eq!(block.total_work(&config), None);
+        assert_eq!(App::chain_work(&[block.clone()], &config), None);
+        // even if it was claimed to be valid
+        let mut app = App::new(config);
+        let chosen = app.choose_chain(CHAIN.clone(), vec![block], true);
+        assert_eq!(chosen, *CHAIN);
+    }
+
+    #[test]
+    fn choose_chain_adopts_a_heavier_valid_chain() {
+        let mut app = App::new(Config::default());
+        let local = CHAIN[..2].to_vec();
+        let chosen = app.choose_chain(local, CHAIN.clone(), true);
+        assert_eq!(chosen.len(), 3);
+
+        let chosen = app.choose_chain(CHAIN[..2].to_vec(), CHAIN.clone(), false);
+        assert_eq!(chosen.len(), 2);
+    }
+
+    #[test]
+    fn choose_chain_rejects_a_tip_with_a_wrong_hash() {
+        let mut app = App::new(Config::default());
+        let chosen = app.choose_chain(CHAIN[..2].to_vec(), tampered_chain(2), true);
+        assert_eq!(chosen.len(), 2);
+    }
+
+    #[test]
+    fn choose_chain_keeps_ours_on_an_identical_tip() {
+        let mut app = App::new(Config::default());
+        let mut remote = CHAIN.clone();
+        remote.insert(1, CHAIN[1].clone());
+        let chosen = app.choose_chain(CHAIN.clone(), remote, true);
+        assert_eq!(chosen.len(), 3);
+    }
+
+    #[test]
+    fn the_hash_is_the_sha256_of_the_canonical_bytes() {
+        for block in CHAIN.iter() {
+            assert_eq!(
+                hex::encode(Sha256::digest(&block.canonical_bytes())),
+                block.hash
+            );
+        }
+        let canonical =
+            String::from_utf8(CHAIN[1].canonical_bytes()).expect("canonical bytes are utf-8");
+        assert!(canonical.starts_with("{\"data\":\"block 1\",\"id\":1,\"nonce\":"));
+    }
+
+    #[test]
+    fn the_genesis_block_has_to_hash_to_the_network_id() {
+        assert_eq!(verify_network_id(None), Ok(()));
+        assert_eq!(verify_network_id(Some(GENESIS_HASH)), Ok(()));
+        let other_network = "ff".repeat(32);
+        assert!(verify_network_id(Some(&other_network)).is_err());
+    }
+
+    #[test]
+    fn the_genesis_block_has_to_meet_the_difficulty() {
+        let genesis = genesis_block();
+        assert_eq!(
+            verify_genesis_difficulty(&genesis, DIFFICULTY_PREFIX),
+            Ok(())
+        );
+
+        let raised = "0".repeat(24);
+        let error = verify_genesis_difficulty(&genesis, &raised)
+            .expect_err("the genesis hash doesn't have 24 leading zero bits");
+        assert!(error.contains("has 17 leading zero bits, but the difficulty requires 24"));
+    }
+
+    #[test]
+    fn mined_hashes_have_the_leading_zeros_the_difficulty_requires() {
+        let binary =
+            |hash: &str| hash_to_binary_representation(&hex::decode(hash).expect("hash is hex"));
+        assert_eq!(leading_zero_bits(&binary(GENESIS_HASH)), 17);
+        for block in CHAIN.iter() {
+            assert!(leading_zero_bits(&binary(&block.hash)) >= DIFFICULTY_PREFIX.len());
+        }
+        assert_eq!(leading_zero_bits("0001"), 3);
+        assert_eq!(leading_zero_bits("1000"), 0);
+    }
+
+    #[test]
+    fn each_difficulty_bit_doubles_the_expected_hashes() {
+        assert_eq!(expected_hashes(0), 1.0);
+        assert_eq!(expected_hashes(8), 256.0);
+        assert_eq!(expected_hashes(DIFFICULTY_PREFIX.len()), 65536.0);
+    }
+
+    #[test]
+    fn mining_records_its_hashrate() {
+        let started = Utc::now().timestamp();
+        mine_block(
+            1,
+            GENESIS_TIMESTAMP,
+            GENESIS_HASH,
+            "block",
+            None,
+            MIN_DIFFICULTY_PREFIX,
+            None,
+        )
+        .expect("mining without a deadline doesn't time out");
+        // other tests mine at the same time, so there can be more samples than ours
+        let history = HASHRATE_HISTORY.lock().expect("can lock hashrate history");
+        assert!(history.iter().any(|sample| sample.timestamp >= started));
+        assert!(history.iter().all(|sample| sample.hashrate > 0.0));
+        assert!(history.len() <= config::get().max_hashrate_samples);
+    }
+
+    #[test]
+    fn mining_gives_up_after_the_deadline() {
+        // more zero bits than a hash has, so no nonce meets it
+        let impossible_prefix = "0".repeat(257);
+        let result = mine_block(
+            1,
+            GENESIS_TIMESTAMP,
+            GENESIS_HASH,
+            "block",
+            None,
+            &impossible_prefix,
+            Some(Instant::now() + Duration::from_millis(100)),
+        );
+        assert_eq!(result, Err(MiningError::Timeout));
+    }
+
+    #[test]
+    fn mining_appends_a_record_to_the_mining_log() {
+        let path = std::env::temp_dir().join(format!(
+            "rust-blockchain-example-{}-mining-log.jsonl",
+            std::process::id()
+        ));
+        let config = Config {
+            mining_log_path: Some(path.to_str().expect("temp dir is utf-8").to_string()),
+            ..Config::default()
+        };
+        let block = Block::next(&CHAIN[2], "logged".to_string(), None, &config)
+            .expect("mining without a deadline doesn't time out");
+        let log = std::fs::read_to_string(&path).expect("can read mining log");
+        std::fs::remove_file(&path).expect("can remove mining log");
+
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 1);
+        let record: serde_json::Value =
+            serde_json::from_str(lines[0]).expect("a record is a line of json");
+        assert_eq!(record["id"], block.id);
+        assert_eq!(record["nonce"], block.nonce);
+        assert_eq!(record["hash"], block.hash);
+        assert_eq!(record["attempts"], block.nonce + 1);
+        assert!(record["duration_ms"].is_u64());
+        assert!(record["hashrate"].is_f64());
+    }
+
+    #[test]
+    fn mining_refuses_to_run_ahead_of_the_clock() {
+        let mut previous = CHAIN[1].clone();
+        // with some slack for the clock ticking during the test
+        previous.timestamp = Utc::now().timestamp() + MAX_FUTURE_DRIFT_SECS + 5;
+        let result = Block::next(&previous, "block".to_string(), None, &Config::default());
+        assert_eq!(result, Err(MiningError::AheadOfClock));
+    }
+
+    #[test]
+    fn testnet_accepts_minimum_difficulty_after_the_reset_timeout() {
+        let genesis = genesis_block();
+        let now = Utc::now().timestamp();
+        // mined at the minimum difficulty, but missing the regular one
+        let min_difficulty_block = |timestamp: i64| {
+            (0..)
+                .map(|i| {
+                    Block::mine(
+                        1,
+                        genesis.hash.clone(),
+                        format!("testnet {}", i),
+                        None,
+                        timestamp,
+                        MIN_DIFFICULTY_PREFIX,
+                        None,
+                    )
+                    .expect("mining without a deadline doesn't time out")
+                    .0
+                })
+                .find(|block| !block.meets_difficulty(DIFFICULTY_PREFIX))
+                .expect("some data gives an easy enough hash")
+        };
+        let testnet = Config {
+            testnet: true,
+            ..Config::default()
+        };
+
+        let block = min_difficulty_block(genesis.timestamp + TESTNET_RESET_TIMEOUT_SECS);
+        assert_eq!(
+            BlockRule::first_failed(&block, &genesis, &testnet, now),
+            None
+        );
+        assert_eq!(
+            BlockRule::first_failed(&block, &genesis, &Config::default(), now),
+            Some(BlockRule::Difficulty)
+        );
+
+        let early = min_difficulty_block(genesis.timestamp + TESTNET_RESET_TIMEOUT_SECS - 1);
+        assert_eq!(
+            BlockRule::first_failed(&early, &genesis, &testnet, now),
+            Some(BlockRule::Difficulty)
+        );
+    }
+}
@@ -0,0 +1,369 @@
+// Maintenance subcommands, which run instead of the node, e.g.
+// `cargo run -- maintenance fix-genesis`
+use super::{
+    config::{self, Config},
+    genesis_block, mine_block, starts_at_genesis, App, Block, BlockRule, InvalidChain,
+    DIFFICULTY_PREFIX, GENESIS_HASH, GENESIS_NONCE, GENESIS_TIMESTAMP,
+};
+use chrono::Utc;
+use log::error;
+use std::{
+    fs,
+    time::{Duration, Instant},
+};
+
+// seconds between the blocks of a generated chain
+const GENERATED_BLOCK_INTERVAL_SECS: i64 = 10;
+
+// runs the given subcommand and returns the process exit code
+pub fn run(args: &[String]) -> i32 {
+    match args.first().map(String::as_str) {
+        Some("fix-genesis") => fix_genesis(),
+        Some("gen-chain") => match (
+            args.get(1).map(|blocks| blocks.parse::<u64>()),
+            args.get(2).map(|seed| seed.parse::<u64>()),
+            args.get(3),
+        ) {
+            (Some(Ok(blocks)), Some(Ok(seed)), Some(path)) => gen_chain(blocks, seed, path),
+            _ => {
+                error!("usage: maintenance gen-chain <blocks> <seed> <path>");
+                1
+            }
+        },
+        Some("bench-validate") => match args.get(1).map(|blocks| blocks.parse::<u64>()) {
+            Some(Ok(blocks)) => bench_validate(blocks),
+            _ => {
+                error!("usage: maintenance bench-validate <blocks>");
+                1
+            }
+        },
+        Some("replay") => match args.get(1) {
+            Some(path) => replay(path),
+            None => {
+                error!("usage: maintenance replay <path>");
+                1
+            }
+        },
+        Some(cmd) => {
+            error!("unknown maintenance command: {}", cmd);
+            1
+        }
+        None => {
+            error!(
+                "missing maintenance command, available: fix-genesis, gen-chain, bench-validate, replay"
+            );
+            1
+        }
+    }
+}
+
+// re-mines the genesis block with the current hashing code and difficulty,
+// and prints the constants to use for it
+fn fix_genesis() -> i32 {
+    let genesis = genesis_block();
+    if genesis.meets_difficulty(DIFFICULTY_PREFIX) && genesis.has_correct_hash() {
+        println!("genesis block is valid, nothing to fix");
+        return 0;
+    }
+    let remined = remined_genesis(&genesis);
+    println!("genesis block is invalid, replace the genesis constants in src/main.rs with:");
+    println!();
+    println!("const GENESIS_NONCE: u64 = {};", remined.nonce);
+    println!("const GENESIS_HASH: &str = \"{}\";", remined.hash);
+    println!();
+    println!(
+        "(currently GENESIS_NONCE = {}, GENESIS_HASH = {})",
+        GENESIS_NONCE, GENESIS_HASH
+    );
+    0
+}
+
+// the genesis block mined at the current difficulty, only its nonce and hash change
+fn remined_genesis(genesis: &Block) -> Block {
+    let record = mine_block(
+        genesis.id,
+        genesis.timestamp,
+        &genesis.previous_hash,
+        &genesis.data,
+        genesis.total_work,
+        DIFFICULTY_PREFIX,
+        None,
+    )
+    .expect("mining without a deadline doesn't time out");
+    Block {
+        nonce: record.nonce,
+        hash: record.hash,
+        ..genesis.clone()
+    }
+}
+
+// mines a chain with the given number of blocks and measures how long validating it takes,
+// broken down by validation rule
+fn bench_validate(blocks: u64) -> i32 {
+    println!("mining {} blocks...", blocks);
+    // with timestamps fixed in the past, since mining several blocks a second would run the
+    // timestamps of the newest ones ahead of the clock
+    let chain = generate_chain(blocks, 1);
+
+    println!("validating...");
+    let benchmark = match benchmark_validation(&chain, config::get(), Utc::now().timestamp()) {
+        Ok(benchmark) => benchmark,
+        Err(e) => {
+            error!("{}", e);
+            return 1;
+        }
+    };
+    println!(
+        "validated {} blocks in {:?}, {:.0} blocks/s",
+        blocks, benchmark.total, benchmark.blocks_per_sec
+    );
+    for (rule, duration) in BlockRule::ALL.iter().zip(benchmark.per_rule.iter()) {
+        println!("{:<14} {:?}", rule.name(), duration);
+    }
+    println!(
+        "validated {} blocks in parallel in {:?}, {:.0} blocks/s",
+        blocks, benchmark.parallel, benchmark.parallel_blocks_per_sec
+    );
+    0
+}
+
+// how long validating a chain took
+struct ValidationBenchmark {
+    total: Duration,
+    blocks_per_sec: f64,
+    // in the order of BlockRule::ALL
+    per_rule: [Duration; BlockRule::ALL.len()],
+    // the same chain validated the way received chains are with parallel_chain_validation
+    parallel: Duration,
+    parallel_blocks_per_sec: f64,
+}
+
+fn benchmark_validation(
+    chain: &[Block],
+    config: &Config,
+    now: i64,
+) -> Result<ValidationBenchmark, InvalidChain> {
+    let mut per_rule = [Duration::default(); BlockRule::ALL.len()];
+    let started = Instant::now();
+    for pair in chain.windows(2) {
+        for (i, rule) in BlockRule::ALL.iter().enumerate() {
+            let rule_started = Instant::now();
+            if !rule.check(&pair[1], &pair[0], config, now) {
+                return Err(InvalidChain::InvalidBlock(pair[1].id, *rule));
+            }
+            per_rule[i] += rule_started.elapsed();
+        }
+    }
+    let total = started.elapsed();
+
+    let started = Instant::now();
+    let parallel_valid = App::is_chain_valid_parallel(chain, config, now);
+    let parallel = started.elapsed();
+    assert!(
+        parallel_valid,
+        "the parallel validation disagrees with the sequential one"
+    );
+
+    let blocks = (chain.len() - 1) as f64;
+    Ok(ValidationBenchmark {
+        total,
+        blocks_per_sec: blocks / total.as_secs_f64(),
+        per_rule,
+        parallel,
+        parallel_blocks_per_sec: blocks / parallel.as_secs_f64(),
+    })
+}
+
+// A chain with the given number of blocks, which only depends on the seed - its timestamps are
+// fixed offsets from genesis, and its data is generated from the seed
+fn generate_chain(blocks: u64, seed: u64) -> Vec<Block> {
+    // xorshift64, which must not be seeded with 0
+    let mut state = seed.max(1);
+    let mut next_random = || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+
+    let mut chain = vec![genesis_block()];
+    for id in 1..=blocks {
+        let previous_hash = chain
+            .last()
+            .expect("there is at least one block")
+            .hash
+            .clone();
+        chain.push(Block::with_timestamp(
+            id,
+            previous_hash,
+            format!("{:016x}", next_random()),
+            None,
+            GENESIS_TIMESTAMP + id as i64 * GENERATED_BLOCK_INTERVAL_SECS,
+        ));
+    }
+    chain
+}
+
+// writes a generated chain to the given path, see generate_chain
+fn gen_chain(blocks: u64, seed: u64, path: &str) -> i32 {
+    let chain = generate_chain(blocks, seed);
+    let json = serde_json::to_string_pretty(&chain).expect("can jsonify chain");
+    if let Err(e) = fs::write(path, json) {
+        error!("could not write chain to {}, {}", path, e);
+        return 1;
+    }
+    println!("wrote chain with {} blocks to {}", blocks, path);
+    0
+}
+
+// runs all validation rules over a chain stored as JSON, e.g. to check that it still validates
+// after a change to the validation code, and reports every block failing any of them
+fn replay(path: &str) -> i32 {
+    let chain = match fs::read(path)
+        .map_err(|e| e.to_string())
+        .and_then(|data| serde_json::from_slice::<Vec<Block>>(&data).map_err(|e| e.to_string()))
+    {
+        Ok(chain) => chain,
+        Err(e) => {
+            error!("could not read chain from {}, {}", path, e);
+            return 1;
+        }
+    };
+    match chain.first() {
+        Some(_) if starts_at_genesis(&chain) => (),
+        Some(_) => {
+            println!("chain in {} has a different genesis block", path);
+            return 1;
+        }
+        None => {
+            println!("chain in {} is empty", path);
+            return 1;
+        }
+    }
+
+    let invalid_blocks = replay_failures(&chain, config::get(), Utc::now().timestamp());
+    for (id, failed) in invalid_blocks.iter() {
+        let failed: Vec<&str> = failed.iter().map(BlockRule::name).collect();
+        println!(
+            "block with id: {} fails the rules: {}",
+            id,
+            failed.join(", ")
+        );
+    }
+    println!(
+        "replayed {} blocks from {}, {} invalid",
+        chain.len(),
+        path,
+        invalid_blocks.len()
+    );
+    if invalid_blocks.is_empty() {
+        0
+    } else {
+        1
+    }
+}
+
+// the id of every block of the chain failing any rule, with all the rules it fails
+fn replay_failures(chain: &[Block], config: &Config, now: i64) -> Vec<(u64, Vec<BlockRule>)> {
+    chain
+        .windows(2)
+        .filter_map(|pair| {
+            let failed: Vec<BlockRule> = BlockRule::trace(&pair[1], &pair[0], config, now)
+                .into_iter()
+                .filter(|(_, passed)| !passed)
+                .map(|(rule, _)| rule)
+                .collect();
+            if failed.is_empty() {
+                None
+            } else {
+                Some((pair[1].id, failed))
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn chain_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "rust-blockchain-example-{}-{}.json",
+            std::process::id(),
+            name
+        ))
+    }
+
+    // generates a chain and returns the written file
+    fn generated_chain_file(blocks: u64, seed: u64, name: &str) -> Vec<u8> {
+        let path = chain_file(name);
+        assert_eq!(
+            gen_chain(blocks, seed, path.to_str().expect("temp dir is utf-8")),
+            0
+        );
+        let content = fs::read(&path).expect("can read generated chain");
+        fs::remove_file(&path).expect("can remove generated chain");
+        content
+    }
+
+    #[test]
+    fn gen_chain_only_depends_on_the_seed() {
+        let first = generated_chain_file(2, 7, "seed-7-first");
+        let second = generated_chain_file(2, 7, "seed-7-second");
+        assert_eq!(first, second);
+        assert_ne!(first, generated_chain_file(2, 8, "seed-8"));
+    }
+
+    #[test]
+    fn generated_chain_replays_valid() {
+        let path = chain_file("replay");
+        let path = path.to_str().expect("temp dir is utf-8");
+        assert_eq!(gen_chain(2, 7, path), 0);
+        let exit_code = replay(path);
+        fs::remove_file(path).expect("can remove generated chain");
+        assert_eq!(exit_code, 0);
+    }
+
+    #[test]
+    fn the_remined_genesis_block_is_valid() {
+        let mut genesis = genesis_block();
+        genesis.hash = "00".repeat(32);
+        assert!(!genesis.has_correct_hash());
+
+        let remined = remined_genesis(&genesis);
+        assert!(remined.meets_difficulty(DIFFICULTY_PREFIX));
+        assert!(remined.has_correct_hash());
+        assert_eq!((remined.id, &remined.data), (genesis.id, &genesis.data));
+    }
+
+    #[test]
+    fn replaying_a_tampered_chain_reports_the_block() {
+        let mut chain = generate_chain(2, 7);
+        chain[1].data = "tampered".to_string();
+        assert_eq!(
+            replay_failures(&chain, &Config::default(), Utc::now().timestamp()),
+            vec![(1, vec![BlockRule::Hash])]
+        );
+
+        let path = chain_file("replay-tampered");
+        fs::write(
+            &path,
+            serde_json::to_vec(&chain).expect("can jsonify chain"),
+        )
+        .expect("can write chain");
+        let exit_code = replay(path.to_str().expect("temp dir is utf-8"));
+        fs::remove_file(&path).expect("can remove chain");
+        assert_eq!(exit_code, 1);
+    }
+
+    #[test]
+    fn bench_validate_measures_a_positive_throughput() {
+        let chain = generate_chain(2, 1);
+        let benchmark = benchmark_validation(&chain, &Config::default(), Utc::now().timestamp())
+            .expect("a generated chain is valid");
+        assert!(benchmark.blocks_per_sec > 0.0);
+        assert!(benchmark.parallel_blocks_per_sec > 0.0);
+        assert_eq!(bench_validate(1), 0);
+    }
+}
@@ -1,16 +1,32 @@
-use super::{App, Block};
+use super::{
+    config::{self, Config},
+    expected_hashes, hash_to_binary_representation, leading_zero_bits, logging, App, Block,
+    BlockRule, MiningError, DIFFICULTY_PREFIX, HASHRATE_HISTORY, LAST_HASHRATE,
+};
+use chrono::Utc;
 use libp2p::{
     floodsub::{Floodsub, FloodsubEvent, Topic},
-    identity,
+    identity::{self, error::SigningError, PublicKey},
     mdns::{Mdns, MdnsEvent},
-    swarm::{NetworkBehaviourEventProcess, Swarm},
-    NetworkBehaviour, PeerId,
+    multiaddr::Protocol,
+    ping::{Ping, PingConfig, PingEvent, PingSuccess},
+    swarm::{NetworkBehaviour, NetworkBehaviourEventProcess, Swarm},
+    Multiaddr, NetworkBehaviour, PeerId,
 };
-use log::{error, info};
+use log::{error, info, warn};
 use once_cell::sync::Lazy;
-use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
-use tokio::sync::mpsc;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs,
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+use tokio::{
+    spawn,
+    sync::mpsc::{self, error::TrySendError},
+};
 
 pub static KEYS: Lazy<identity::Keypair> = Lazy::new(identity::Keypair::generate_ed25519);
 pub static PEER_ID: Lazy<PeerId> = Lazy::new(|| PeerId::from(KEYS.public()));
@@ -21,6 +37,42 @@ pub static BLOCK_TOPIC: Lazy<Topic> = Lazy::new(|| Topic::new("blocks"));
 pub struct ChainResponse {
     pub blocks: Vec<Block>,
     pub receiver: String,
+    // floodsub doesn't authenticate a message's source, so responses are signed by the sender,
+    // with the protobuf-encoded public key its peer id derives from
+    #[serde(default)]
+    pub public_key: String,
+    #[serde(default)]
+    pub signature: String,
+}
+
+impl ChainResponse {
+    pub fn signed(blocks: Vec<Block>, receiver: String) -> Result<Self, SigningError> {
+        let signature = KEYS.sign(&Self::signed_bytes(&blocks, &receiver))?;
+        Ok(Self {
+            blocks,
+            receiver,
+            public_key: hex::encode(KEYS.public().into_protobuf_encoding()),
+            signature: hex::encode(signature),
+        })
+    }
+
+    fn signed_bytes(blocks: &[Block], receiver: &str) -> Vec<u8> {
+        serde_json::to_vec(&(blocks, receiver)).expect("can jsonify response")
+    }
+
+    // the peer which signed the response, if the signature is valid
+    pub fn signer(&self) -> Option<PeerId> {
+        let public_key = hex::decode(&self.public_key)
+            .ok()
+            .and_then(|key| PublicKey::from_protobuf_encoding(&key).ok())?;
+        let signature = hex::decode(&self.signature).ok()?;
+        public_key
+            .verify(
+                &Self::signed_bytes(&self.blocks, &self.receiver),
+                &signature,
+            )
+            .then(|| PeerId::from(public_key))
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -28,8 +80,31 @@ pub struct LocalChainRequest {
     pub from_peer_id: String,
 }
 
+pub struct ValidatedChain {
+    pub blocks: Vec<Block>,
+    pub is_valid: bool,
+}
+
+// a block received from a peer, waiting to be validated in the main loop
+pub struct InboundBlock {
+    pub source: PeerId,
+    pub block: Block,
+}
+
+// the outcome of mining a block on the mining thread, see Config::mining_thread
+pub struct MinedBlock {
+    pub id: u64,
+    pub data: String,
+    pub result: Result<Block, MiningError>,
+}
+
 pub enum EventType {
     LocalChainResponse(ChainResponse),
+    MinedBlock(MinedBlock),
+    ValidatedChain(ValidatedChain),
+    InboundBlock(InboundBlock),
+    ConnectionEstablished(PeerId),
+    RetryFutureBlocks,
     Input(String),
     Init,
 }
@@ -38,19 +113,54 @@ pub enum EventType {
 pub struct AppBehaviour {
     pub floodsub: Floodsub,
     pub mdns: Mdns,
+    pub ping: Ping,
     #[behaviour(ignore)]
-    pub response_sender: mpsc::UnboundedSender<ChainResponse>,
+    pub response_sender: mpsc::Sender<ChainResponse>,
     #[behaviour(ignore)]
-    pub init_sender: mpsc::UnboundedSender<bool>,
+    pub validated_sender: mpsc::UnboundedSender<ValidatedChain>,
+    #[behaviour(ignore)]
+    pub block_sender: mpsc::Sender<InboundBlock>,
+    #[behaviour(ignore)]
+    pub mined_sender: mpsc::UnboundedSender<MinedBlock>,
+    // whether a block is being mined on the mining thread, blocks to create meanwhile are queued
+    #[behaviour(ignore)]
+    pub mining: bool,
+    #[behaviour(ignore)]
+    pub queued_blocks: VecDeque<String>,
     #[behaviour(ignore)]
     pub app: App,
+    // peers we simulate a network partition with, see handle_partition
+    #[behaviour(ignore)]
+    pub partitioned_peers: HashSet<PeerId>,
+    // round trip time of the last successful ping to each peer
+    #[behaviour(ignore)]
+    pub latencies: HashMap<PeerId, Duration>,
+    // peers still to ask for their chain on startup, in order, if the current one doesn't respond
+    #[behaviour(ignore)]
+    pub sync_candidates: Vec<PeerId>,
+    #[behaviour(ignore)]
+    pub sync_deadline: Option<i64>,
+    // how many blocks of each peer were dropped, because they came in faster than we validate -
+    // past Config::max_dropped_blocks_per_peer, the peer's blocks are ignored for a while
+    #[behaviour(ignore)]
+    pub dropped_blocks: HashMap<PeerId, DroppedBlocks>,
+}
+
+// the blocks of a peer dropped within the current window, see Config::dropped_blocks_window_secs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DroppedBlocks {
+    pub count: u64,
+    // when the first of them was dropped
+    pub since: i64,
 }
 
 impl AppBehaviour {
     pub async fn new(
         app: App,
-        response_sender: mpsc::UnboundedSender<ChainResponse>,
-        init_sender: mpsc::UnboundedSender<bool>,
+        response_sender: mpsc::Sender<ChainResponse>,
+        validated_sender: mpsc::UnboundedSender<ValidatedChain>,
+        block_sender: mpsc::Sender<InboundBlock>,
+        mined_sender: mpsc::UnboundedSender<MinedBlock>,
     ) -> Self {
         let mut behaviour = Self {
             app,
@@ -58,41 +168,209 @@ impl AppBehaviour {
             mdns: Mdns::new(Default::default())
                 .await
                 .expect("can create mdns"),
+            ping: Ping::new(PingConfig::new()),
             response_sender,
-            init_sender,
+            validated_sender,
+            block_sender,
+            mined_sender,
+            mining: false,
+            queued_blocks: VecDeque::new(),
+            partitioned_peers: HashSet::new(),
+            latencies: HashMap::new(),
+            sync_candidates: vec![],
+            sync_deadline: None,
+            dropped_blocks: HashMap::new(),
         };
         behaviour.floodsub.subscribe(CHAIN_TOPIC.clone());
         behaviour.floodsub.subscribe(BLOCK_TOPIC.clone());
 
         behaviour
     }
+
+    // queues a block published by source for validation, unless source is ignored at the moment
+    fn handle_block_message(&mut self, source: PeerId, block: Block, now: i64) {
+        let max_dropped = self.app.config.max_dropped_blocks_per_peer;
+        let window_secs = self.app.config.dropped_blocks_window_secs;
+        // a message's source isn't authenticated, so a peer is only ignored until the window
+        // ends - blocks sent under an honest peer's id can't shut it out for good
+        if let Some(dropped) = self.dropped_blocks.get(&source) {
+            if now >= dropped.since + window_secs {
+                self.dropped_blocks.remove(&source);
+            } else if max_dropped > 0 && dropped.count >= max_dropped {
+                info!(
+                    "ignoring block with id: {} from {}, too many of their blocks were dropped",
+                    block.id, source
+                );
+                return;
+            }
+        }
+        info!("received new block from {}", source);
+        // checked before queueing, so blocks without any work can't fill the queue
+        if self.app.config.verify_pow_first && !block.has_valid_pow(&self.app.config) {
+            warn!(
+                "dropping block with id: {} from {}, invalid proof of work",
+                block.id, source
+            );
+            quarantine_block(&block, "proof of work", &source, &self.app.config);
+            return;
+        }
+        // the queue is bounded, so a peer sending blocks faster than we validate them
+        // can't make it grow without limit
+        match self.block_sender.try_send(InboundBlock { source, block }) {
+            Ok(()) => (),
+            Err(TrySendError::Full(inbound)) => {
+                let dropped = self.dropped_blocks.entry(source).or_insert(DroppedBlocks {
+                    count: 0,
+                    since: now,
+                });
+                dropped.count += 1;
+                self.app.metrics.blocks_dropped += 1;
+                warn!(
+                    "inbound block queue is full, dropping block with id: {} from {} ({} dropped from them)",
+                    inbound.block.id, source, dropped.count
+                );
+                if dropped.count == max_dropped {
+                    warn!(
+                        "ignoring blocks from {} for the next {}s",
+                        source,
+                        dropped.since + window_secs - now
+                    );
+                }
+            }
+            Err(e) => error!("error sending inbound block via channel, {}", e),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct QuarantinedBlock<'a> {
+    reason: &'a str,
+    source: String,
+    received_at: i64,
+    block: &'a Block,
+}
+
+// writes a rejected block to the quarantine dir, if it's enabled and not full
+fn quarantine_block(block: &Block, reason: &str, source: &PeerId, config: &Config) {
+    let dir = match &config.quarantine_dir {
+        Some(dir) => Path::new(dir),
+        None => return,
+    };
+    if let Err(e) = fs::create_dir_all(dir) {
+        error!("could not create quarantine dir, {}", e);
+        return;
+    }
+    let quarantined = fs::read_dir(dir)
+        .map(|entries| entries.count())
+        .unwrap_or(0);
+    if quarantined >= config.max_quarantined_blocks {
+        warn!("quarantine is full, dropping block with id: {}", block.id);
+        return;
+    }
+    let received_at = Utc::now().timestamp();
+    let entry = QuarantinedBlock {
+        reason,
+        source: source.to_string(),
+        received_at,
+        block,
+    };
+    let path = dir.join(format!("{}-{}-{}.json", received_at, block.id, quarantined));
+    let json = serde_json::to_string_pretty(&entry).expect("can jsonify quarantined block");
+    match fs::write(&path, json) {
+        Ok(()) => info!(
+            "quarantined block with id: {} in {}",
+            block.id,
+            path.display()
+        ),
+        Err(e) => error!("could not quarantine block with id: {}, {}", block.id, e),
+    }
+}
+
+// parses a message from a peer, which fails on unknown fields in strict_json mode
+fn from_json<T: DeserializeOwned>(data: &[u8], strict: bool) -> serde_json::Result<T> {
+    if !strict {
+        return serde_json::from_slice(data);
+    }
+    let mut unknown_fields = vec![];
+    let mut deserializer = serde_json::Deserializer::from_slice(data);
+    let value = serde_ignored::deserialize(&mut deserializer, |path| {
+        unknown_fields.push(path.to_string())
+    })?;
+    deserializer.end()?;
+    if let Some(field) = unknown_fields.first() {
+        return Err(serde::de::Error::custom(format!(
+            "unknown field: {}",
+            field
+        )));
+    }
+    Ok(value)
 }
 
 // incoming event handler
 impl NetworkBehaviourEventProcess<FloodsubEvent> for AppBehaviour {
     fn inject_event(&mut self, event: FloodsubEvent) {
         if let FloodsubEvent::Message(msg) = event {
-            if let Ok(resp) = serde_json::from_slice::<ChainResponse>(&msg.data) {
+            if self.partitioned_peers.contains(&msg.source) {
+                return;
+            }
+            let strict = self.app.config.strict_json;
+            if let Ok(resp) = from_json::<ChainResponse>(&msg.data, strict) {
                 if resp.receiver == PEER_ID.to_string() {
+                    if !is_trusted_chain_response(&resp, &self.app.config) {
+                        warn!(
+                            "ignoring chain from {}, it's not signed by a trusted peer",
+                            msg.source
+                        );
+                        return;
+                    }
                     info!("Response from {}:", msg.source);
+                    // the remaining candidates are kept, until the chain turns out to be valid
+                    self.sync_deadline = None;
                     resp.blocks.iter().for_each(|r| info!("{:?}", r));
 
-                    self.app.blocks = self.app.choose_chain(self.app.blocks.clone(), resp.blocks);
+                    // validate off the event loop, the result is picked up in the main loop
+                    let validated_sender = self.validated_sender.clone();
+                    let config = self.app.config.clone();
+                    let now = Utc::now().timestamp();
+                    spawn(async move {
+                        let is_valid =
+                            App::is_chain_valid_chunked(&resp.blocks, &config, now).await;
+                        if let Err(e) = validated_sender.send(ValidatedChain {
+                            blocks: resp.blocks,
+                            is_valid,
+                        }) {
+                            error!("error sending validated chain via channel, {}", e);
+                        }
+                    });
                 }
-            } else if let Ok(resp) = serde_json::from_slice::<LocalChainRequest>(&msg.data) {
-                info!("sending local chain to {}", msg.source.to_string());
+            } else if let Ok(resp) = from_json::<LocalChainRequest>(&msg.data, strict) {
+                info!("sending local chain to {}", msg.source);
                 let peer_id = resp.from_peer_id;
                 if PEER_ID.to_string() == peer_id {
-                    if let Err(e) = self.response_sender.send(ChainResponse {
-                        blocks: self.app.blocks.clone(),
-                        receiver: msg.source.to_string(),
-                    }) {
-                        error!("error sending response via channel, {}", e);
+                    // the channel is bounded, if it's full we drop the response instead of piling them up
+                    let response = match ChainResponse::signed(
+                        self.app.blocks.clone(),
+                        msg.source.to_string(),
+                    ) {
+                        Ok(response) => response,
+                        Err(e) => {
+                            error!("could not sign chain response, {}", e);
+                            return;
+                        }
+                    };
+                    match self.response_sender.try_send(response) {
+                        Ok(()) => (),
+                        Err(TrySendError::Full(_)) => {
+                            warn!(
+                                "response channel is full, dropping response to {}",
+                                msg.source
+                            )
+                        }
+                        Err(e) => error!("error sending response via channel, {}", e),
                     }
                 }
-            } else if let Ok(block) = serde_json::from_slice::<Block>(&msg.data) {
-                info!("received new block from {}", msg.source.to_string());
-                self.app.try_add_block(block);
+            } else if let Ok(block) = from_json::<Block>(&msg.data, strict) {
+                self.handle_block_message(msg.source, block, Utc::now().timestamp());
             }
         }
     }
@@ -103,6 +381,9 @@ impl NetworkBehaviourEventProcess<MdnsEvent> for AppBehaviour {
         match event {
             MdnsEvent::Discovered(discovered_list) => {
                 for (peer, _addr) in discovered_list {
+                    if self.partitioned_peers.contains(&peer) {
+                        continue;
+                    }
                     self.floodsub.add_node_to_partial_view(peer);
                 }
             }
@@ -117,6 +398,52 @@ impl NetworkBehaviourEventProcess<MdnsEvent> for AppBehaviour {
     }
 }
 
+impl NetworkBehaviourEventProcess<PingEvent> for AppBehaviour {
+    fn inject_event(&mut self, event: PingEvent) {
+        match event.result {
+            Ok(PingSuccess::Ping { rtt }) => {
+                self.latencies.insert(event.peer, rtt);
+            }
+            Ok(PingSuccess::Pong) => (),
+            Err(e) => {
+                warn!("ping to {} failed: {}", event.peer, e);
+                self.latencies.remove(&event.peer);
+            }
+        }
+    }
+}
+
+pub fn handle_inbound_block(inbound: InboundBlock, swarm: &mut Swarm<AppBehaviour>) {
+    let InboundBlock { source, block } = inbound;
+    let app = &mut swarm.behaviour_mut().app;
+    if let Err(rule) = app.try_add_block(block.clone(), source, Utc::now().timestamp()) {
+        quarantine_block(&block, rule.name(), &source, &app.config);
+    }
+}
+
+// adds the queued future blocks which are no longer ahead of our clock, quarantining invalid ones
+pub fn handle_retry_future_blocks(swarm: &mut Swarm<AppBehaviour>) {
+    let app = &mut swarm.behaviour_mut().app;
+    for (InboundBlock { source, block }, rule) in app.retry_future_blocks(Utc::now().timestamp()) {
+        quarantine_block(&block, rule.name(), &source, &app.config);
+    }
+}
+
+// disconnects a peer which just connected, if that makes us connected to more than max_peers
+pub fn handle_connection_established(peer_id: PeerId, swarm: &mut Swarm<AppBehaviour>) {
+    let peers = swarm.network_info().num_peers();
+    if peers > swarm.behaviour().app.config.max_peers as usize {
+        info!(
+            "already connected to {} peers, disconnecting {}",
+            peers - 1,
+            peer_id
+        );
+        if swarm.disconnect_peer_id(peer_id).is_err() {
+            warn!("could not disconnect {}", peer_id);
+        }
+    }
+}
+
 pub fn get_list_peers(swarm: &Swarm<AppBehaviour>) -> Vec<String> {
     info!("Discovered Peers:");
     let nodes = swarm.behaviour().mdns.discovered_nodes();
@@ -127,9 +454,128 @@ pub fn get_list_peers(swarm: &Swarm<AppBehaviour>) -> Vec<String> {
     unique_peers.iter().map(|p| p.to_string()).collect()
 }
 
+fn is_trusted_sync_peer(peer_id: &PeerId, config: &Config) -> bool {
+    let trusted = &config.trusted_sync_peers;
+    trusted.is_empty() || trusted.contains(&peer_id.to_string())
+}
+
+// the sender of a message can be forged, so it's the response's signer which has to be trusted
+fn is_trusted_chain_response(resp: &ChainResponse, config: &Config) -> bool {
+    if config.trusted_sync_peers.is_empty() {
+        return true;
+    }
+    resp.signer()
+        .map(|signer| is_trusted_sync_peer(&signer, config))
+        .unwrap_or(false)
+}
+
+// the trusted peers to ask for their chain, the ones with the lowest measured latency first
+pub fn sync_peers(swarm: &Swarm<AppBehaviour>) -> Vec<PeerId> {
+    let behaviour = swarm.behaviour();
+    let discovered = behaviour.mdns.discovered_nodes().cloned().collect();
+    order_sync_peers(discovered, &behaviour.latencies, &behaviour.app.config)
+}
+
+fn order_sync_peers(
+    discovered: HashSet<PeerId>,
+    latencies: &HashMap<PeerId, Duration>,
+    config: &Config,
+) -> Vec<PeerId> {
+    let mut peers: Vec<PeerId> = discovered
+        .into_iter()
+        .filter(|peer| is_trusted_sync_peer(peer, config))
+        .collect();
+    if config.prefer_low_latency_sync {
+        // peers without a measured latency go last
+        peers.sort_by_key(|peer| latencies.get(peer).cloned().unwrap_or(Duration::MAX));
+    }
+    peers
+}
+
+// asks the next sync candidate for its chain
+pub fn request_chain(swarm: &mut Swarm<AppBehaviour>) {
+    let behaviour = swarm.behaviour_mut();
+    if behaviour.sync_candidates.is_empty() {
+        behaviour.sync_deadline = None;
+        return;
+    }
+    let peer_id = behaviour.sync_candidates.remove(0);
+    info!("requesting chain from {}", peer_id);
+    let req = LocalChainRequest {
+        from_peer_id: peer_id.to_string(),
+    };
+    let json = serde_json::to_string(&req).expect("can jsonify request");
+    behaviour
+        .floodsub
+        .publish(CHAIN_TOPIC.clone(), json.as_bytes());
+    behaviour.sync_deadline = Some(Utc::now().timestamp() + behaviour.app.config.sync_timeout_secs);
+}
+
+// falls back to the next sync candidate, if the requested peer didn't respond in time
+pub fn retry_chain_request(swarm: &mut Swarm<AppBehaviour>) {
+    match swarm.behaviour().sync_deadline {
+        Some(deadline) if Utc::now().timestamp() >= deadline => {
+            warn!("no chain received in time, asking the next peer");
+            request_chain(swarm);
+        }
+        _ => (),
+    }
+}
+
 pub fn handle_print_peers(swarm: &Swarm<AppBehaviour>) {
     let peers = get_list_peers(swarm);
-    peers.iter().for_each(|p| info!("{}", p));
+    let latencies = &swarm.behaviour().latencies;
+    peers.iter().for_each(|p| {
+        match p
+            .parse::<PeerId>()
+            .ok()
+            .and_then(|peer_id| latencies.get(&peer_id))
+        {
+            Some(rtt) => info!("{} (latency: {:?})", p, rtt),
+            None => info!("{}", p),
+        }
+    });
+}
+
+// prints our own and our peers' addresses, including their peer ids, to share with other nodes
+pub fn handle_export_peers(swarm: &mut Swarm<AppBehaviour>) {
+    info!("Peer addresses:");
+    exported_addresses(swarm)
+        .iter()
+        .for_each(|addr| info!("{}", addr));
+}
+
+fn exported_addresses(swarm: &mut Swarm<AppBehaviour>) -> Vec<Multiaddr> {
+    let with_peer_id =
+        |addr: &Multiaddr, peer_id: &PeerId| addr.clone().with(Protocol::P2p((*peer_id).into()));
+    let mut addrs: Vec<Multiaddr> = swarm
+        .listeners()
+        .map(|addr| with_peer_id(addr, &PEER_ID))
+        .collect();
+    let peers: HashSet<PeerId> = swarm.behaviour().mdns.discovered_nodes().cloned().collect();
+    for peer_id in peers {
+        for addr in swarm.behaviour_mut().mdns.addresses_of_peer(&peer_id) {
+            addrs.push(with_peer_id(&addr, &peer_id));
+        }
+    }
+    addrs
+}
+
+// prints the last measured round trip time to a peer, peers are pinged periodically
+pub fn handle_ping(cmd: &str, swarm: &Swarm<AppBehaviour>) {
+    if let Some(peer) = cmd.strip_prefix("ping") {
+        let peer_id = match peer.trim().parse::<PeerId>() {
+            Ok(peer_id) => peer_id,
+            Err(e) => {
+                error!("invalid peer id: {}", e);
+                return;
+            }
+        };
+        match swarm.behaviour().latencies.get(&peer_id) {
+            Some(rtt) => info!("latency to {}: {:?}", peer_id, rtt),
+            None => info!("no latency measured for {} yet", peer_id),
+        }
+    }
 }
 
 pub fn handle_print_chain(swarm: &Swarm<AppBehaviour>) {
@@ -139,24 +585,1105 @@ pub fn handle_print_chain(swarm: &Swarm<AppBehaviour>) {
     info!("{}", pretty_json);
 }
 
+// prints a block's hash in hex and binary, to show how it meets the difficulty
+pub fn handle_explain_hash(cmd: &str, swarm: &Swarm<AppBehaviour>) {
+    if let Some(id) = cmd.strip_prefix("explain h") {
+        let id = match id.trim().parse::<u64>() {
+            Ok(id) => id,
+            Err(e) => {
+                error!("invalid block id: {}", e);
+                return;
+            }
+        };
+        let block = match swarm.behaviour().app.blocks.iter().find(|b| b.id == id) {
+            Some(block) => block,
+            None => {
+                error!("no block with id: {}", id);
+                return;
+            }
+        };
+        let binary_hash = match hex::decode(&block.hash) {
+            Ok(hash) => hash_to_binary_representation(&hash),
+            Err(e) => {
+                error!("block with id: {} has a hash which isn't hex: {}", id, e);
+                return;
+            }
+        };
+        let leading_zeros = leading_zero_bits(&binary_hash);
+        info!("hash:   {}", block.hash);
+        info!(
+            "binary: {}|{}",
+            &binary_hash[..leading_zeros],
+            &binary_hash[leading_zeros..]
+        );
+        info!(
+            "leading zero bits: {}, required: {}",
+            leading_zeros,
+            DIFFICULTY_PREFIX.len()
+        );
+    }
+}
+
+// recomputes the hash of every block in the local chain and reports the ones not matching
+// their stored hash, which would mean corruption or a change to the hashing code
+pub fn handle_audit_hashes(swarm: &Swarm<AppBehaviour>) {
+    let blocks = &swarm.behaviour().app.blocks;
+    let mismatches = hash_mismatches(blocks);
+    for (block, hash) in mismatches.iter() {
+        warn!(
+            "block with id: {} is stored with hash {}, but hashes to {}",
+            block.id, block.hash, hash
+        );
+    }
+    info!(
+        "audited the hashes of {} blocks, {} mismatches",
+        blocks.len(),
+        mismatches.len()
+    );
+}
+
+// the blocks whose stored hash isn't what they hash to, with what they hash to
+fn hash_mismatches(blocks: &[Block]) -> Vec<(&Block, String)> {
+    blocks
+        .iter()
+        .map(|block| (block, block.recompute_hash()))
+        .filter(|(block, hash)| *hash != block.hash)
+        .collect()
+}
+
+// what an attestation's signature covers, signed as its JSON serialization (in this field order)
+#[derive(Debug, Serialize)]
+struct AttestedTip<'a> {
+    height: u64,
+    tip_hash: &'a str,
+    timestamp: i64,
+}
+
+// prints a statement, signed with the node's key, that it had the given tip at this time
+pub fn handle_attest(swarm: &Swarm<AppBehaviour>) {
+    let tip = swarm
+        .behaviour()
+        .app
+        .blocks
+        .last()
+        .expect("there is at least one block");
+    match attestation(tip, Utc::now().timestamp()) {
+        Ok(attestation) => info!("{}", attestation),
+        Err(e) => error!("could not sign attestation, {}", e),
+    }
+}
+
+// the statement that tip was our tip at timestamp, with our signature of it
+fn attestation(tip: &Block, timestamp: i64) -> Result<serde_json::Value, SigningError> {
+    let statement = AttestedTip {
+        height: tip.id,
+        tip_hash: &tip.hash,
+        timestamp,
+    };
+    let signed = serde_json::to_string(&statement).expect("can jsonify attestation");
+    let signature = KEYS.sign(signed.as_bytes())?;
+    Ok(serde_json::json!({
+        "height": statement.height,
+        "tip_hash": statement.tip_hash,
+        "timestamp": statement.timestamp,
+        "peer_id": PEER_ID.to_string(),
+        "public_key": hex::encode(KEYS.public().into_protobuf_encoding()),
+        "signature": hex::encode(signature),
+    }))
+}
+
+pub fn handle_show_config() {
+    info!("Config:");
+    config::show(config::get())
+        .lines()
+        .for_each(|line| info!("{}", line));
+}
+
+pub fn handle_set_log_level(cmd: &str) {
+    if let Some(filters) = cmd.strip_prefix("loglevel") {
+        let filters = filters.trim();
+        if filters.is_empty() {
+            error!("missing log filters, e.g. loglevel info,rust_blockchain_example::p2p=debug");
+            return;
+        }
+        logging::set_filters(filters);
+        info!("log filters set to {}", filters);
+    }
+}
+
+pub fn handle_print_difficulty() {
+    print_difficulty(DIFFICULTY_PREFIX.len());
+}
+
+// estimates the block time at another difficulty, using the hashrate measured when mining
+pub fn handle_simulate_difficulty(cmd: &str) {
+    if let Some(bits) = cmd.strip_prefix("simulate difficulty") {
+        match bits.trim().parse::<usize>() {
+            Ok(bits) if bits <= 256 => print_difficulty(bits),
+            Ok(bits) => error!(
+                "a hash has 256 bits, {} leading zero bits are impossible",
+                bits
+            ),
+            Err(e) => error!("invalid number of bits: {}", e),
+        }
+    }
+}
+
+fn print_difficulty(bits: usize) {
+    let expected_hashes = expected_hashes(bits);
+    info!("difficulty: {} leading zero bits", bits);
+    info!("expected hashes per block: {}", expected_hashes);
+    match *LAST_HASHRATE.lock().expect("can lock hashrate") {
+        Some(hashrate) => info!(
+            "expected time per block: {:.2}s at {:.0} hashes/s",
+            expected_block_secs(bits, hashrate),
+            hashrate
+        ),
+        None => info!("expected time per block: unknown, no block mined yet"),
+    }
+}
+
+// how long mining a block with the given difficulty takes on average, at hashrate hashes/s
+fn expected_block_secs(bits: usize, hashrate: f64) -> f64 {
+    expected_hashes(bits) / hashrate
+}
+
+// prints a snapshot of the node's metrics as a flat JSON object
+pub fn handle_print_metrics(swarm: &Swarm<AppBehaviour>) {
+    info!("{}", metrics_snapshot(swarm.behaviour()));
+}
+
+fn metrics_snapshot(behaviour: &AppBehaviour) -> serde_json::Value {
+    let peers: HashSet<&PeerId> = behaviour.mdns.discovered_nodes().collect();
+    let metrics = &behaviour.app.metrics;
+    serde_json::json!({
+        "height": behaviour.app.blocks.last().map(|b| b.id),
+        "peers": peers.len(),
+        "future_blocks": behaviour.app.future_blocks.len(),
+        "blocks_mined": metrics.blocks_mined,
+        "blocks_received": metrics.blocks_received,
+        "chains_adopted": metrics.chains_adopted,
+        "blocks_dropped": metrics.blocks_dropped,
+        "hashrate": *LAST_HASHRATE.lock().expect("can lock hashrate"),
+    })
+}
+
+pub fn handle_print_hashrate_history() {
+    let history = HASHRATE_HISTORY.lock().expect("can lock hashrate history");
+    info!(
+        "{}",
+        serde_json::to_string(&*history).expect("can jsonify hashrate history")
+    );
+}
+
+// estimates the memory used by the in-memory chain and block queues, by their serialized size
+pub fn handle_print_memstats(swarm: &Swarm<AppBehaviour>) {
+    let app = &swarm.behaviour().app;
+    let (blocks_size, future_blocks_size) = memory_usage(app);
+    info!("Memory usage (estimated from serialized size):");
+    info!(
+        "blocks: {} bytes ({} blocks)",
+        blocks_size,
+        app.blocks.len()
+    );
+    info!(
+        "future blocks: {} bytes ({} blocks)",
+        future_blocks_size,
+        app.future_blocks.len()
+    );
+}
+
+// the serialized size of the chain and of the queued future blocks
+fn memory_usage(app: &App) -> (usize, usize) {
+    let future_blocks: Vec<&Block> = app
+        .future_blocks
+        .iter()
+        .map(|inbound| &inbound.block)
+        .collect();
+    (
+        serialized_size(&app.blocks),
+        serialized_size(&future_blocks),
+    )
+}
+
+fn serialized_size<T: Serialize>(value: &T) -> usize {
+    serde_json::to_vec(value).map(|v| v.len()).unwrap_or(0)
+}
+
 pub fn handle_create_block(cmd: &str, swarm: &mut Swarm<AppBehaviour>) {
+    if swarm.behaviour().app.config.replica {
+        error!("this node is a read replica, creating blocks is disabled");
+        return;
+    }
     if let Some(data) = cmd.strip_prefix("create b") {
         let behaviour = swarm.behaviour_mut();
-        let latest_block = behaviour
-            .app
-            .blocks
-            .last()
-            .expect("there is at least one block");
-        let block = Block::new(
-            latest_block.id + 1,
-            latest_block.hash.clone(),
-            data.to_owned(),
+        // blocks are produced one at a time, so they don't compete for the same height
+        if behaviour.mining {
+            info!("a block is already being mined, queueing this one");
+            behaviour.queued_blocks.push_back(data.to_owned());
+            return;
+        }
+        mine_next_block(data.to_owned(), swarm);
+    }
+}
+
+// mines a block on top of the current tip, on the mining thread if it's enabled
+fn mine_next_block(data: String, swarm: &mut Swarm<AppBehaviour>) {
+    let behaviour = swarm.behaviour_mut();
+    let latest_block = behaviour
+        .app
+        .blocks
+        .last()
+        .expect("there is at least one block");
+    let config = &behaviour.app.config;
+    let deadline = match config.max_mining_secs {
+        0 => None,
+        secs => Some(Instant::now() + Duration::from_secs(secs)),
+    };
+    if config.mining_thread {
+        spawn_miner(
+            latest_block.clone(),
+            data,
+            deadline,
+            behaviour.mined_sender.clone(),
+            config.clone(),
+        );
+        behaviour.mining = true;
+        return;
+    }
+    let id = latest_block.id + 1;
+    let result = Block::next(latest_block, data.clone(), deadline, config);
+    handle_mined_block(MinedBlock { id, data, result }, swarm);
+}
+
+// mines the block following previous on a new OS thread, and sends it back to the event loop
+fn spawn_miner(
+    previous: Block,
+    data: String,
+    deadline: Option<Instant>,
+    mined_sender: mpsc::UnboundedSender<MinedBlock>,
+    config: Config,
+) {
+    let spawned = thread::Builder::new()
+        .name("miner".to_string())
+        .spawn(move || {
+            lower_priority(config.mining_thread_nice);
+            let result = Block::next(&previous, data.clone(), deadline, &config);
+            if let Err(e) = mined_sender.send(MinedBlock {
+                id: previous.id + 1,
+                data,
+                result,
+            }) {
+                error!("error sending mined block via channel, {}", e);
+            }
+        });
+    if let Err(e) = spawned {
+        error!("could not spawn mining thread, {}", e);
+    }
+}
+
+// on linux, nice only applies to the calling thread
+#[cfg(unix)]
+fn lower_priority(nice: i32) {
+    if nice == 0 {
+        return;
+    }
+    // SAFETY: nice has no memory safety preconditions
+    if unsafe { libc::nice(nice) } == -1 {
+        warn!(
+            "could not change the mining thread's priority, {}",
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(not(unix))]
+fn lower_priority(nice: i32) {
+    if nice != 0 {
+        warn!("changing the mining thread's priority is only supported on unix");
+    }
+}
+
+// appends a block we mined to the chain and broadcasts it, then mines the next queued one
+pub fn handle_mined_block(mined: MinedBlock, swarm: &mut Swarm<AppBehaviour>) {
+    let behaviour = swarm.behaviour_mut();
+    behaviour.mining = false;
+    match mined.result {
+        Ok(block) => {
+            let latest_block = behaviour
+                .app
+                .blocks
+                .last()
+                .expect("there is at least one block");
+            // the chain can change while mining on the mining thread
+            if block.previous_hash != latest_block.hash {
+                info!(
+                    "the chain's tip changed while mining block with id: {}, mining it again on the new tip",
+                    block.id
+                );
+                mine_next_block(mined.data, swarm);
+                return;
+            }
+            let json = serde_json::to_string(&block).expect("can jsonify request");
+            behaviour.app.blocks.push(block);
+            behaviour.app.metrics.blocks_mined += 1;
+            info!("broadcasting new block");
+            behaviour
+                .floodsub
+                .publish(BLOCK_TOPIC.clone(), json.as_bytes());
+        }
+        Err(e) => error!("giving up on block with id: {}, {}", mined.id, e),
+    }
+    if let Some(data) = swarm.behaviour_mut().queued_blocks.pop_front() {
+        mine_next_block(data, swarm);
+    }
+}
+
+// prints the hex of the bytes a block's hash is computed from, so other tools can verify it
+pub fn handle_dump_block(cmd: &str, swarm: &Swarm<AppBehaviour>) {
+    if let Some(id) = cmd.strip_prefix("dump b") {
+        let id = match id.trim().parse::<u64>() {
+            Ok(id) => id,
+            Err(e) => {
+                error!("invalid block id: {}", e);
+                return;
+            }
+        };
+        match swarm.behaviour().app.blocks.iter().find(|b| b.id == id) {
+            Some(block) => {
+                info!("canonical: {}", hex::encode(block.canonical_bytes()));
+                info!("sha256:    {}", block.hash);
+            }
+            None => error!("no block with id: {}", id),
+        }
+    }
+}
+
+pub fn handle_explain_block(cmd: &str, swarm: &Swarm<AppBehaviour>) {
+    if let Some(id) = cmd.strip_prefix("explain b") {
+        let id = match id.trim().parse::<u64>() {
+            Ok(id) => id,
+            Err(e) => {
+                error!("invalid block id: {}", e);
+                return;
+            }
+        };
+        let blocks = &swarm.behaviour().app.blocks;
+        let block = match blocks.iter().find(|b| b.id == id) {
+            Some(block) => block,
+            None => {
+                error!("no block with id: {}", id);
+                return;
+            }
+        };
+        let previous_block = match id
+            .checked_sub(1)
+            .and_then(|prev| blocks.iter().find(|b| b.id == prev))
+        {
+            Some(previous_block) => previous_block,
+            None => {
+                info!(
+                    "block with id: {} is the genesis block, it isn't validated",
+                    id
+                );
+                return;
+            }
+        };
+        info!("Validation trace for block {}:", id);
+        let config = &swarm.behaviour().app.config;
+        BlockRule::trace(block, previous_block, config, Utc::now().timestamp())
+            .iter()
+            .for_each(|(rule, passed)| {
+                info!(
+                    "{:<14} {}",
+                    rule.name(),
+                    if *passed { "pass" } else { "FAIL" }
+                )
+            });
+    }
+}
+
+// Simulates a network partition for testing - messages from the given peers are ignored
+// and nothing is sent to them anymore, until the partition is healed
+#[cfg(debug_assertions)]
+pub fn handle_partition(cmd: &str, swarm: &mut Swarm<AppBehaviour>) {
+    if let Some(peers) = cmd.strip_prefix("partition") {
+        let behaviour = swarm.behaviour_mut();
+        for peer in peers.split_whitespace() {
+            match peer.parse::<PeerId>() {
+                Ok(peer_id) => {
+                    behaviour.floodsub.remove_node_from_partial_view(&peer_id);
+                    behaviour.partitioned_peers.insert(peer_id);
+                    info!("partitioned from {}", peer_id);
+                }
+                Err(e) => error!("invalid peer id: {}, {}", peer, e),
+            }
+        }
+    }
+}
+
+#[cfg(debug_assertions)]
+pub fn handle_heal(swarm: &mut Swarm<AppBehaviour>) {
+    let behaviour = swarm.behaviour_mut();
+    let discovered: HashSet<PeerId> = behaviour.mdns.discovered_nodes().cloned().collect();
+    let healed: Vec<PeerId> = behaviour.partitioned_peers.drain().collect();
+    for peer_id in healed {
+        info!("healed partition with {}", peer_id);
+        if discovered.contains(&peer_id) {
+            behaviour.floodsub.add_node_to_partial_view(peer_id);
+            // ask for their chain, so diverged chains converge via choose_chain
+            let req = LocalChainRequest {
+                from_peer_id: peer_id.to_string(),
+            };
+            let json = serde_json::to_string(&req).expect("can jsonify request");
+            behaviour
+                .floodsub
+                .publish(CHAIN_TOPIC.clone(), json.as_bytes());
+        }
+    }
+}
+
+// verifies a chain stored in a JSON file, without touching the local chain
+pub fn handle_verify_file(cmd: &str) {
+    if let Some(path) = cmd.strip_prefix("verify f") {
+        let path = path.trim();
+        match verify_file(path, config::get(), Utc::now().timestamp()) {
+            Ok(blocks) => info!("chain in {} with {} blocks is valid", path, blocks),
+            Err(e) => error!("{}", e),
+        }
+    }
+}
+
+// verifies the chain in a JSON file, returns its number of blocks if it's valid
+fn verify_file(path: &str, config: &Config, now: i64) -> Result<usize, String> {
+    let chain = std::fs::read(path)
+        .map_err(|e| e.to_string())
+        .and_then(|data| serde_json::from_slice::<Vec<Block>>(&data).map_err(|e| e.to_string()))
+        .map_err(|e| format!("could not read chain from {}: {}", path, e))?;
+    App::verify_chain(&chain, config, now)
+        .map_err(|e| format!("chain in {} is invalid: {}", path, e))?;
+    Ok(chain.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        genesis_block,
+        tests::{tampered_chain, CHAIN},
+    };
+    use libp2p::{
+        core::{
+            muxing::StreamMuxerBox,
+            transport::{Boxed, MemoryTransport},
+            upgrade,
+        },
+        floodsub::FloodsubMessage,
+        futures::StreamExt,
+        mplex,
+        noise::{Keypair, NoiseConfig, X25519Spec},
+        swarm::{SwarmBuilder, SwarmEvent},
+        Transport,
+    };
+    use tokio::time::timeout;
+
+    // the receiving ends of a behaviour's channels
+    struct Receivers {
+        response_rcv: mpsc::Receiver<ChainResponse>,
+        block_rcv: mpsc::Receiver<InboundBlock>,
+        mined_rcv: mpsc::UnboundedReceiver<MinedBlock>,
+    }
+
+    // a behaviour with the genesis block, and channels sized like the node's
+    async fn test_behaviour(config: Config) -> (AppBehaviour, Receivers) {
+        let (response_sender, response_rcv) = mpsc::channel(config.response_channel_capacity);
+        let (validated_sender, _) = mpsc::unbounded_channel();
+        let (block_sender, block_rcv) = mpsc::channel(config.inbound_block_queue_capacity);
+        let (mined_sender, mined_rcv) = mpsc::unbounded_channel();
+        let mut app = App::new(config);
+        app.genesis();
+        let behaviour = AppBehaviour::new(
+            app,
+            response_sender,
+            validated_sender,
+            block_sender,
+            mined_sender,
+        )
+        .await;
+        (
+            behaviour,
+            Receivers {
+                response_rcv,
+                block_rcv,
+                mined_rcv,
+            },
+        )
+    }
+
+    // like the node's transport, but connecting in memory instead of via tcp
+    fn memory_transport(keys: &identity::Keypair) -> Boxed<(PeerId, StreamMuxerBox)> {
+        let auth_keys = Keypair::<X25519Spec>::new()
+            .into_authentic(keys)
+            .expect("can create auth keys");
+        MemoryTransport
+            .upgrade(upgrade::Version::V1)
+            .authenticate(NoiseConfig::xx(auth_keys).into_authenticated())
+            .multiplex(mplex::MplexConfig::new())
+            .boxed()
+    }
+
+    fn build_swarm<B: NetworkBehaviour>(keys: &identity::Keypair, behaviour: B) -> Swarm<B> {
+        SwarmBuilder::new(
+            memory_transport(keys),
+            behaviour,
+            PeerId::from(keys.public()),
+        )
+        .executor(Box::new(|fut| {
+            spawn(fut);
+        }))
+        .build()
+    }
+
+    // a swarm of a test_behaviour
+    async fn test_swarm(config: Config) -> (Swarm<AppBehaviour>, Receivers) {
+        let (behaviour, receivers) = test_behaviour(config).await;
+        (build_swarm(&KEYS, behaviour), receivers)
+    }
+
+    // starts listening on a new in-memory address and returns it
+    async fn listen(swarm: &mut Swarm<AppBehaviour>) -> Multiaddr {
+        swarm
+            .listen_on("/memory/0".parse().expect("is a multiaddr"))
+            .expect("can listen in memory");
+        loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = swarm.select_next_some().await {
+                return address;
+            }
+        }
+    }
+
+    // speaks the protocols a node's connections need, but ignores what it receives
+    #[derive(NetworkBehaviour)]
+    struct Dialer {
+        floodsub: Floodsub,
+        ping: Ping,
+    }
+
+    impl NetworkBehaviourEventProcess<FloodsubEvent> for Dialer {
+        fn inject_event(&mut self, _event: FloodsubEvent) {}
+    }
+
+    impl NetworkBehaviourEventProcess<PingEvent> for Dialer {
+        fn inject_event(&mut self, _event: PingEvent) {}
+    }
+
+    // a peer driven in the background after dialing address
+    fn spawn_dialer(address: &Multiaddr) -> PeerId {
+        let keys = identity::Keypair::generate_ed25519();
+        let behaviour = Dialer {
+            floodsub: Floodsub::new(PeerId::from(keys.public())),
+            ping: Ping::new(PingConfig::new()),
+        };
+        let mut dialer = build_swarm(&keys, behaviour);
+        dialer.dial_addr(address.clone()).expect("can dial");
+        spawn(async move {
+            loop {
+                dialer.select_next_some().await;
+            }
+        });
+        PeerId::from(keys.public())
+    }
+
+    // a floodsub message from source, as inject_event receives it
+    fn floodsub_message<T: Serialize>(source: PeerId, message: &T) -> FloodsubEvent {
+        FloodsubEvent::Message(FloodsubMessage {
+            source,
+            data: serde_json::to_vec(message).expect("can jsonify message"),
+            sequence_number: vec![],
+            topics: vec![CHAIN_TOPIC.clone()],
+        })
+    }
+
+    #[test]
+    fn signed_chain_responses_name_their_signer() {
+        let response = ChainResponse::signed(vec![genesis_block()], "receiver".to_string())
+            .expect("can sign response");
+        assert_eq!(response.signer(), Some(*PEER_ID));
+
+        let mut tampered = ChainResponse::signed(vec![genesis_block()], "receiver".to_string())
+            .expect("can sign response");
+        tampered.blocks[0].data = "tampered".to_string();
+        assert_eq!(tampered.signer(), None);
+
+        let unsigned = ChainResponse {
+            blocks: vec![genesis_block()],
+            receiver: "receiver".to_string(),
+            public_key: String::new(),
+            signature: String::new(),
+        };
+        assert_eq!(unsigned.signer(), None);
+    }
+
+    #[tokio::test]
+    async fn peers_flooding_the_queue_are_ignored_until_the_window_ends() {
+        let config = Config {
+            inbound_block_queue_capacity: 1,
+            max_dropped_blocks_per_peer: 3,
+            dropped_blocks_window_secs: 60,
+            ..Config::default()
+        };
+        let (mut behaviour, mut receivers) = test_behaviour(config).await;
+        let block = CHAIN[1].clone();
+        let peer = PeerId::random();
+        let now = Utc::now().timestamp();
+
+        // one block fits into the queue, the others are dropped
+        for _ in 0..4 {
+            behaviour.handle_block_message(peer, block.clone(), now);
+        }
+        assert_eq!(
+            behaviour.dropped_blocks.get(&peer),
+            Some(&DroppedBlocks {
+                count: 3,
+                since: now
+            })
+        );
+        assert_eq!(behaviour.app.metrics.blocks_dropped, 3);
+        assert!(receivers.block_rcv.try_recv().is_ok());
+
+        // the queue has room again, but the peer is ignored for the rest of the window
+        behaviour.handle_block_message(peer, block.clone(), now + 59);
+        assert!(receivers.block_rcv.try_recv().is_err());
+        behaviour.handle_block_message(PeerId::random(), block.clone(), now + 59);
+        assert!(receivers.block_rcv.try_recv().is_ok());
+
+        behaviour.handle_block_message(peer, block, now + 60);
+        assert!(receivers.block_rcv.try_recv().is_ok());
+        assert!(!behaviour.dropped_blocks.contains_key(&peer));
+    }
+
+    #[tokio::test]
+    async fn blocks_without_proof_of_work_are_not_queued() {
+        let (mut behaviour, mut receivers) = test_behaviour(Config::default()).await;
+        let mut block = CHAIN[1].clone();
+        block.nonce += 1;
+        behaviour.handle_block_message(PeerId::random(), block, Utc::now().timestamp());
+        assert!(receivers.block_rcv.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn replicas_serve_their_chain_but_never_create_blocks() {
+        let config = Config {
+            replica: true,
+            ..Config::default()
+        };
+        let (mut swarm, mut receivers) = test_swarm(config).await;
+
+        handle_create_block("create b data", &mut swarm);
+        let behaviour = swarm.behaviour_mut();
+        assert!(!behaviour.mining);
+        assert!(behaviour.queued_blocks.is_empty());
+        assert_eq!(behaviour.app.blocks, vec![genesis_block()]);
+
+        handle_print_chain(&swarm);
+        let request = LocalChainRequest {
+            from_peer_id: PEER_ID.to_string(),
+        };
+        NetworkBehaviourEventProcess::inject_event(
+            swarm.behaviour_mut(),
+            floodsub_message(PeerId::random(), &request),
+        );
+        let response = receivers
+            .response_rcv
+            .try_recv()
+            .expect("a replica responds with its chain");
+        assert_eq!(response.blocks, vec![genesis_block()]);
+    }
+
+    #[tokio::test]
+    async fn future_blocks_failing_on_retry_are_quarantined() {
+        let dir = std::env::temp_dir().join(format!(
+            "rust-blockchain-example-{}-quarantine",
+            std::process::id()
+        ));
+        let config = Config {
+            quarantine_dir: Some(dir.to_str().expect("temp dir is utf-8").to_string()),
+            ..Config::default()
+        };
+        let (mut swarm, _receivers) = test_swarm(config).await;
+        let block = tampered_chain(1)[1].clone();
+        let source = PeerId::random();
+        // queued as if it was ahead of the clock when it came in
+        let received_at =
+            block.timestamp - swarm.behaviour().app.config.future_block_grace_window_secs;
+        assert_eq!(
+            swarm
+                .behaviour_mut()
+                .app
+                .try_add_block(block.clone(), source, received_at),
+            Ok(())
+        );
+
+        handle_retry_future_blocks(&mut swarm);
+        let entries = fs::read_dir(&dir)
+            .expect("can read quarantine dir")
+            .map(|entry| entry.expect("can read quarantine entry").path())
+            .collect::<Vec<_>>();
+        let quarantined: serde_json::Value =
+            serde_json::from_slice(&fs::read(&entries[0]).expect("can read quarantined block"))
+                .expect("quarantined block is json");
+        fs::remove_dir_all(&dir).expect("can remove quarantine dir");
+
+        assert_eq!(entries.len(), 1);
+        assert_eq!(quarantined["reason"], BlockRule::Hash.name());
+        assert_eq!(quarantined["source"], source.to_string());
+        assert_eq!(quarantined["block"]["data"], block.data);
+        assert!(swarm.behaviour().app.future_blocks.is_empty());
+    }
+
+    #[test]
+    fn only_responses_signed_by_trusted_peers_are_trusted() {
+        let signed = ChainResponse::signed(vec![genesis_block()], "receiver".to_string())
+            .expect("can sign response");
+        let unsigned = ChainResponse {
+            blocks: vec![genesis_block()],
+            receiver: "receiver".to_string(),
+            public_key: String::new(),
+            signature: String::new(),
+        };
+        let trusting = |peer: PeerId| Config {
+            trusted_sync_peers: vec![peer.to_string()],
+            ..Config::default()
+        };
+
+        assert!(is_trusted_chain_response(&signed, &trusting(*PEER_ID)));
+        assert!(!is_trusted_chain_response(
+            &signed,
+            &trusting(PeerId::random())
+        ));
+        assert!(!is_trusted_chain_response(&unsigned, &trusting(*PEER_ID)));
+        // without trusted peers, every response is
+        assert!(is_trusted_chain_response(&unsigned, &Config::default()));
+    }
+
+    #[test]
+    fn simulated_block_time_scales_with_the_difficulty() {
+        assert_eq!(expected_block_secs(8, 256.0), 1.0);
+        assert_eq!(expected_block_secs(16, 1024.0), 64.0);
+        assert_eq!(expected_block_secs(17, 1024.0), 128.0);
+    }
+
+    #[test]
+    fn attestations_are_signed_over_the_tip() {
+        let tip = &CHAIN[2];
+        let attestation = attestation(tip, 1_000).expect("can sign attestation");
+        assert_eq!(attestation["tip_hash"], tip.hash);
+        assert_eq!(attestation["peer_id"], PEER_ID.to_string());
+        let signature = hex::decode(attestation["signature"].as_str().expect("signature is hex"))
+            .expect("signature is hex");
+
+        let statement = |tip_hash| {
+            serde_json::to_string(&AttestedTip {
+                height: tip.id,
+                tip_hash,
+                timestamp: 1_000,
+            })
+            .expect("can jsonify attestation")
+        };
+        assert!(KEYS
+            .public()
+            .verify(statement(&tip.hash).as_bytes(), &signature));
+        assert!(!KEYS
+            .public()
+            .verify(statement(&CHAIN[1].hash).as_bytes(), &signature));
+    }
+
+    #[test]
+    fn the_fastest_peer_is_asked_for_its_chain_first() {
+        let (fast, slow, unmeasured, untrusted) = (
+            PeerId::random(),
+            PeerId::random(),
+            PeerId::random(),
+            PeerId::random(),
+        );
+        let latencies = [
+            (slow, Duration::from_millis(300)),
+            (fast, Duration::from_millis(20)),
+            (untrusted, Duration::from_millis(1)),
+        ]
+        .into_iter()
+        .collect();
+        let config = Config {
+            trusted_sync_peers: [fast, slow, unmeasured]
+                .iter()
+                .map(PeerId::to_string)
+                .collect(),
+            ..Config::default()
+        };
+        let discovered = [unmeasured, slow, untrusted, fast].into_iter().collect();
+        assert_eq!(
+            order_sync_peers(discovered, &latencies, &config),
+            vec![fast, slow, unmeasured]
+        );
+    }
+
+    #[tokio::test]
+    async fn connections_beyond_max_peers_are_closed() {
+        let config = Config {
+            max_peers: 2,
+            ..Config::default()
+        };
+        let (mut swarm, _receivers) = test_swarm(config).await;
+        let address = listen(&mut swarm).await;
+        for _ in 0..3 {
+            spawn_dialer(&address);
+        }
+
+        let (mut established, mut closed) = (0, 0);
+        timeout(Duration::from_secs(10), async {
+            while established < 3 || closed < 1 {
+                match swarm.select_next_some().await {
+                    SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                        established += 1;
+                        handle_connection_established(peer_id, &mut swarm);
+                    }
+                    SwarmEvent::ConnectionClosed { .. } => closed += 1,
+                    _ => (),
+                }
+            }
+        })
+        .await
+        .expect("the third connection is closed");
+        assert_eq!(closed, 1);
+        assert_eq!(swarm.network_info().num_peers(), 2);
+    }
+
+    // the ids of the chain's blocks, which have to be one per height
+    fn block_ids(swarm: &Swarm<AppBehaviour>) -> Vec<u64> {
+        swarm.behaviour().app.blocks.iter().map(|b| b.id).collect()
+    }
+
+    #[tokio::test]
+    async fn blocks_mined_on_a_stale_tip_are_mined_again() {
+        let (mut swarm, _receivers) = test_swarm(Config::default()).await;
+        let genesis = genesis_block();
+        let result = Block::next(&genesis, "mined".to_string(), None, &Config::default());
+        // a block from a peer arrives while mining
+        swarm.behaviour_mut().app.blocks.push(CHAIN[1].clone());
+
+        handle_mined_block(
+            MinedBlock {
+                id: 1,
+                data: "mined".to_string(),
+                result,
+            },
+            &mut swarm,
+        );
+        assert_eq!(block_ids(&swarm), vec![0, 1, 2]);
+        let tip = &swarm.behaviour().app.blocks[2];
+        assert_eq!(tip.previous_hash, CHAIN[1].hash);
+        assert_eq!(tip.data, "mined");
+    }
+
+    #[tokio::test]
+    async fn blocks_created_while_mining_get_their_own_height() {
+        let config = Config {
+            mining_thread: true,
+            ..Config::default()
+        };
+        let (mut swarm, mut receivers) = test_swarm(config).await;
+        handle_create_block("create b first", &mut swarm);
+        handle_create_block("create b second", &mut swarm);
+        assert_eq!(swarm.behaviour().queued_blocks.len(), 1);
+
+        for _ in 0..2 {
+            let mined = receivers.mined_rcv.recv().await.expect("a block is mined");
+            handle_mined_block(mined, &mut swarm);
+        }
+        assert_eq!(block_ids(&swarm), vec![0, 1, 2]);
+        assert!(!swarm.behaviour().mining);
+        assert!(swarm.behaviour().queued_blocks.is_empty());
+    }
+
+    #[test]
+    fn verifying_a_file_reports_the_first_invalid_block() {
+        let verify = |name: &str, chain: &[Block]| {
+            let path = std::env::temp_dir().join(format!(
+                "rust-blockchain-example-{}-{}.json",
+                std::process::id(),
+                name
+            ));
+            fs::write(&path, serde_json::to_vec(chain).expect("can jsonify chain"))
+                .expect("can write chain");
+            let result = verify_file(
+                path.to_str().expect("temp dir is utf-8"),
+                &Config::default(),
+                Utc::now().timestamp(),
+            );
+            fs::remove_file(&path).expect("can remove chain");
+            result
+        };
+
+        assert_eq!(verify("verify-good", &CHAIN), Ok(3));
+
+        let mut tampered = tampered_chain(1);
+        tampered[2].data = "tampered".to_string();
+        let error = verify("verify-tampered", &tampered).expect_err("the chain is tampered");
+        assert!(error.ends_with("is invalid: block with id: 1 fails the hash rule"));
+    }
+
+    #[test]
+    fn memory_usage_grows_with_the_blocks() {
+        let mut app = App::new(Config::default());
+        app.genesis();
+        let (blocks_size, future_blocks_size) = memory_usage(&app);
+        assert!(blocks_size > 0);
+
+        app.blocks.push(CHAIN[1].clone());
+        let block = CHAIN[2].clone();
+        let now = block.timestamp - app.config.future_block_grace_window_secs;
+        assert_eq!(app.try_add_block(block, PeerId::random(), now), Ok(()));
+        assert_eq!(app.future_blocks.len(), 1);
+
+        let (grown_blocks_size, grown_future_blocks_size) = memory_usage(&app);
+        assert!(grown_blocks_size > blocks_size);
+        assert!(grown_future_blocks_size > future_blocks_size);
+    }
+
+    #[tokio::test]
+    async fn flooding_chain_requests_stays_within_the_response_channel() {
+        let config = Config {
+            response_channel_capacity: 2,
+            ..Config::default()
+        };
+        let (mut behaviour, mut receivers) = test_behaviour(config).await;
+        let request = LocalChainRequest {
+            from_peer_id: PEER_ID.to_string(),
+        };
+        for _ in 0..5 {
+            NetworkBehaviourEventProcess::inject_event(
+                &mut behaviour,
+                floodsub_message(PeerId::random(), &request),
+            );
+        }
+        for _ in 0..2 {
+            assert!(receivers.response_rcv.try_recv().is_ok());
+        }
+        assert!(receivers.response_rcv.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn metrics_are_a_flat_object_of_numbers() {
+        let (mut behaviour, _receivers) = test_behaviour(Config::default()).await;
+        behaviour.app.metrics.blocks_received = 3;
+        let snapshot = metrics_snapshot(&behaviour);
+        let snapshot = snapshot.as_object().expect("metrics are an object");
+
+        let mut keys: Vec<&str> = snapshot.keys().map(String::as_str).collect();
+        keys.sort_unstable();
+        assert_eq!(
+            keys,
+            vec![
+                "blocks_dropped",
+                "blocks_mined",
+                "blocks_received",
+                "chains_adopted",
+                "future_blocks",
+                "hashrate",
+                "height",
+                "peers",
+            ]
+        );
+        assert_eq!(snapshot["height"], 0);
+        assert_eq!(snapshot["blocks_received"], 3);
+        for (key, value) in snapshot {
+            match key.as_str() {
+                // unknown until a block was mined
+                "hashrate" => assert!(value.is_null() || value.is_f64()),
+                _ => assert!(value.is_u64(), "{} is {}", key, value),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn the_latency_to_connected_peers_is_measured() {
+        let (mut swarm, _receivers) = test_swarm(Config::default()).await;
+        let address = listen(&mut swarm).await;
+        let peer = spawn_dialer(&address);
+
+        timeout(Duration::from_secs(10), async {
+            while !swarm.behaviour().latencies.contains_key(&peer) {
+                // ping results are handled within the swarm, without an event waking us up
+                let _ = timeout(Duration::from_millis(100), swarm.select_next_some()).await;
+            }
+        })
+        .await
+        .expect("the peer is pinged");
+        assert!(swarm.behaviour().latencies[&peer] > Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn exported_addresses_include_our_listen_address() {
+        let (mut swarm, _receivers) = test_swarm(Config::default()).await;
+        let address = listen(&mut swarm).await;
+        let ours = address.with(Protocol::P2p((*PEER_ID).into()));
+        assert!(exported_addresses(&mut swarm).contains(&ours));
+    }
+
+    #[test]
+    fn the_audit_catches_a_corrupted_hash() {
+        assert!(hash_mismatches(&CHAIN).is_empty());
+
+        let mut corrupted = CHAIN.clone();
+        corrupted[1].hash = "00".repeat(32);
+        let mismatches = hash_mismatches(&corrupted);
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].0.id, 1);
+        assert_eq!(mismatches[0].1, CHAIN[1].hash);
+    }
+
+    #[tokio::test]
+    async fn the_event_loop_keeps_running_while_mining_on_a_thread() {
+        let config = Config {
+            mining_thread: true,
+            ..Config::default()
+        };
+        let (mut swarm, mut receivers) = test_swarm(config).await;
+        let started = Instant::now();
+        handle_create_block("create b data", &mut swarm);
+        // the block is mined after the command returns
+        assert!(swarm.behaviour().mining);
+        assert_eq!(swarm.behaviour().app.blocks.len(), 1);
+
+        let mut ticks = 0;
+        let mined = loop {
+            tokio::select! {
+                mined = receivers.mined_rcv.recv() => break mined.expect("a block is mined"),
+                _ = tokio::time::sleep(Duration::from_millis(10)) => ticks += 1,
+            }
+        };
+        let mining = started.elapsed();
+        // the runtime ticked throughout, with plenty of slack for a busy machine
+        assert!(
+            ticks >= mining.as_millis() / 50,
+            "only {} ticks while mining for {:?}",
+            ticks,
+            mining
+        );
+        handle_mined_block(mined, &mut swarm);
+        assert_eq!(swarm.behaviour().app.blocks.len(), 2);
+    }
+
+    #[test]
+    fn strict_json_rejects_unknown_fields() {
+        let mut json = serde_json::to_value(&CHAIN[1]).expect("can serialize block");
+        json["extra"] = serde_json::json!(1);
+        let data = serde_json::to_vec(&json).expect("can serialize json");
+
+        assert!(from_json::<Block>(&data, true).is_err());
+        assert_eq!(
+            from_json::<Block>(&data, false).expect("relaxed parsing ignores the field"),
+            CHAIN[1]
         );
-        let json = serde_json::to_string(&block).expect("can jsonify request");
-        behaviour.app.blocks.push(block);
-        info!("broadcasting new block");
-        behaviour
-            .floodsub
-            .publish(BLOCK_TOPIC.clone(), json.as_bytes());
     }
 }
@@ -0,0 +1,39 @@
+use log::{error, info};
+use pprof::ProfilerGuard;
+use std::fs::File;
+
+// samples per second taken while mining
+const PROFILING_FREQUENCY: i32 = 1000;
+
+pub fn start() -> Option<ProfilerGuard<'static>> {
+    match ProfilerGuard::new(PROFILING_FREQUENCY) {
+        Ok(guard) => Some(guard),
+        Err(e) => {
+            error!("could not start mining profiler, {}", e);
+            None
+        }
+    }
+}
+
+// writes the profile of mining the block with the given id to mining-<id>.svg
+pub fn write_flamegraph(profiler: Option<ProfilerGuard<'static>>, id: u64) {
+    let profiler = match profiler {
+        Some(profiler) => profiler,
+        None => return,
+    };
+    let report = match profiler.report().build() {
+        Ok(report) => report,
+        Err(e) => {
+            error!("could not build mining profile, {}", e);
+            return;
+        }
+    };
+    let path = format!("mining-{}.svg", id);
+    match File::create(&path) {
+        Ok(file) => match report.flamegraph(file) {
+            Ok(()) => info!("wrote mining flamegraph to {}", path),
+            Err(e) => error!("could not write mining flamegraph, {}", e),
+        },
+        Err(e) => error!("could not create {}, {}", path, e),
+    }
+}